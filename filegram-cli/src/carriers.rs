@@ -0,0 +1,32 @@
+use std::error::Error;
+
+use clap::Args;
+use filegram::{carrier::Carrier, decode};
+
+use crate::utils;
+
+#[derive(Args)]
+pub struct Carriers {
+    /// Image to check, e.g. as downloaded from a host
+    file: String,
+}
+
+impl Carriers {
+    /// Prints every carrier with whether it still holds a readable header
+    /// and payload, failing when none does.
+    pub fn execute(self) -> Result<(), Box<dyn Error>> {
+        let png = utils::read_file(&self.file)?;
+        let survived = decode::survey(&png);
+        for carrier in Carrier::ALL {
+            let state = match survived.contains(&carrier) {
+                true => "intact",
+                false => "missing",
+            };
+            println!("{carrier}: {state}");
+        }
+        if survived.is_empty() {
+            Err("No carrier of the image holds a file")?
+        }
+        Ok(())
+    }
+}
@@ -1,18 +1,41 @@
+mod carriers;
+mod key;
+mod keyring;
+mod keyslot;
+mod plan;
 mod utils;
 
 use std::{
     fs::{self, File},
-    io::{self, BufReader},
-    path::Path,
+    io::Cursor,
+    time::SystemTime,
 };
 
 use std::error::Error;
 
+use carriers::Carriers;
 use clap::{Args, Parser, Subcommand};
+#[cfg(feature = "pkcs11")]
+use filegram::encryption::pkcs11;
 use filegram::{
-    decode, encode,
-    encryption::{Cipher, Key},
+    calibration,
+    carrier::Carrier,
+    decode, deniable,
+    encode::{self, CompressionType, Encoder, FilterType, PngOptions},
+    encryption::{self, Cipher, Key},
+    envelope, file,
+    header::Header,
+    keyslot::{self as slots, KeySlot, Secret},
+    label::Label,
+    mmap,
+    padding::Bucket,
 };
+use image::RgbImage;
+use key::KeyCommand;
+use keyring::Keyring;
+use keyslot::Keyslot;
+use plan::Plan;
+use zeroize::Zeroizing;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -26,6 +49,10 @@ impl Cli {
         match self.command {
             Command::Encode(encode) => encode.execute(),
             Command::Decode(decode) => decode.execute(),
+            Command::Keyslot(keyslot) => keyslot.execute(),
+            Command::Key(key) => key.execute(),
+            Command::Plan(plan) => plan.execute(),
+            Command::Carriers(carriers) => carriers.execute(),
         }
     }
 }
@@ -34,6 +61,14 @@ impl Cli {
 enum Command {
     Encode(Encode),
     Decode(Decode),
+    /// Manage the secrets that unlock an encrypted image
+    Keyslot(Keyslot),
+    /// Manage the keyring and convert key files to and from other formats
+    Key(KeyCommand),
+    /// Print the dimensions and number of images a file needs on a host
+    Plan(Plan),
+    /// Print which carriers of an image still hold its file
+    Carriers(Carriers),
 }
 
 trait CommandTrait {
@@ -47,39 +82,399 @@ struct Encode {
     file: Option<String>,
     #[arg(short, long)]
     output: Option<String>,
-    #[arg(short, long)]
+    #[arg(short, long, group = "encryption")]
     encrypted: bool,
+    #[arg(
+        short,
+        long,
+        requires = "encrypted",
+        help = "keep file name and size inside the encrypted payload"
+    )]
+    private: bool,
+    #[arg(
+        long,
+        requires = "encryption",
+        help = "pad to hide the file size: 'pow2' or a step like '4mib', implies --private"
+    )]
+    pad: Option<Bucket>,
+    #[arg(long, requires = "encrypted", help = "also unlock with a passphrase")]
+    passphrase: bool,
+    #[arg(
+        long,
+        requires = "encrypted",
+        help = "also unlock with a printed recovery code"
+    )]
+    recovery: bool,
+    #[arg(
+        long,
+        requires_all = ["encrypted", "threshold"],
+        help = "split the key file into this many share files"
+    )]
+    shares: Option<u8>,
+    #[arg(
+        long,
+        requires = "shares",
+        help = "number of shares needed to rebuild the key"
+    )]
+    threshold: Option<u8>,
+    #[arg(
+        long,
+        requires = "encrypted",
+        conflicts_with = "shares",
+        help = "path to a master key that opens every image, instead of a new key file"
+    )]
+    master: Option<String>,
+    #[arg(
+        long,
+        requires = "encrypted",
+        help = "don't store the key in the local keyring"
+    )]
+    no_keyring: bool,
+    #[arg(
+        long,
+        requires = "encrypted",
+        conflicts_with_all = ["master", "shares", "passphrase", "recovery"],
+        help = "path to a team key, deriving the image key from the file so equal files give equal images"
+    )]
+    convergent: Option<String>,
+    #[cfg(feature = "pkcs11")]
+    #[arg(
+        long,
+        requires = "encrypted",
+        conflicts_with_all = ["master", "shares", "convergent"],
+        help = "PKCS#11 URI of a token key to wrap the image key with, instead of a key file"
+    )]
+    pkcs11_uri: Option<String>,
+    #[arg(
+        long,
+        group = "encryption",
+        help = "encrypt with a passphrase, leaving room for a hidden file that needs another one"
+    )]
+    deniable: bool,
+    #[arg(
+        long,
+        requires = "deniable",
+        help = "path to a file hidden behind a second passphrase"
+    )]
+    hidden: Option<String>,
+    #[arg(
+        short = 'z',
+        long,
+        conflicts_with = "deniable",
+        help = "compress the file before encoding"
+    )]
+    compress: bool,
+    #[arg(
+        long,
+        conflicts_with_all = ["profile", "compression", "filter"],
+        help = "path to a PNG image to hide the file in, leaving its pixels as they are"
+    )]
+    cover: Option<String>,
+    #[arg(
+        long,
+        requires = "cover",
+        help = "where the cover image holds the file: 'chunk' (default) or 'trailer' after its end"
+    )]
+    carrier: Option<Carrier>,
+    #[arg(long, help = "shape the image to fit a host profile, see `fig plan`")]
+    profile: Option<String>,
+    #[arg(
+        long,
+        requires = "profile",
+        help = "path to a JSON file of extra profiles"
+    )]
+    profiles: Option<String>,
+    #[arg(
+        long,
+        value_parser = utils::parse_compression,
+        help = "PNG compression: 'fast' (default), 'default', 'best', 'none' or a level from 1 to 9"
+    )]
+    compression: Option<CompressionType>,
+    #[arg(
+        long,
+        value_parser = utils::parse_filter,
+        help = "PNG filter: 'adaptive' (default), 'none', 'sub', 'up', 'avg' or 'paeth'"
+    )]
+    filter: Option<FilterType>,
+    #[arg(
+        long,
+        conflicts_with = "encryption",
+        help = "store rows as running sums per channel, which shrinks text with adaptive filtering"
+    )]
+    planar: bool,
+    #[arg(
+        long,
+        conflicts_with_all = ["profile", "cover"],
+        help = "draw the file name, size and date above the data, for people who come across the image"
+    )]
+    label: bool,
+    #[arg(
+        long,
+        conflicts_with_all = ["profile", "cover"],
+        help = "frame the data with markers, so it decodes after being rescaled, cropped or screenshotted"
+    )]
+    markers: bool,
+    #[arg(
+        long,
+        requires = "markers",
+        value_parser = clap::value_parser!(u32).range(1..=64),
+        help = "pixels per side of every data pixel drawn within the markers [default: 1]"
+    )]
+    module: Option<u32>,
 }
 
 impl CommandTrait for Encode {
     fn execute(self) -> Result<(), Box<dyn Error>> {
         let output = self.output.clone().unwrap_or_else(|| self.default_output());
-        let data = if let Some(file) = self.file {
-            utils::read_to_end(File::open(file)?)
+        let input = utils::read_input(self.file.as_deref())?;
+        let file_name = self.file.as_deref().and_then(utils::file_name);
+        if self.deniable {
+            return self.encode_deniable(file_name, &input, &output);
+        }
+        let options = self.options();
+        let private = options.is_private();
+        let mut encoding = options.encoding(file_name, input.len())?;
+        encoding.header.banner_rows = self.banner_rows();
+        // private images don't show their file name or size anywhere
+        let label = self.label(
+            encoding.header.file_name.clone(),
+            (!private).then_some(input.len() as u64),
+        );
+        let mut output = output;
+        // the stages are part of the associated data the key slots and a
+        // convergent key are bound to, so they're recorded before the
+        // cipher is chosen
+        let aad = encoding.header.associated_data();
+        let data = encoding.plaintext(&input)?;
+        let cipher = if !self.encrypted {
+            None
+        } else if let Some(path) = &self.convergent {
+            let team = utils::load_key(path)?;
+            if !self.no_keyring {
+                Keyring::open()?.store(&team)?;
+            }
+            let cipher = Cipher::convergent(&team, &data, &aad);
+            let slot = KeySlot::seal_convergent(cipher.key(), &team, &aad)?;
+            encoding.header.key_slots.push(slot);
+            if self.output.is_none() && private {
+                // a random name would make equal images look different
+                output = format!("{}.png", cipher.key().id());
+            }
+            Some(cipher)
         } else {
-            utils::read_to_end(io::stdin())
-        }?;
-        let rgb = if self.encrypted {
             let cipher = Cipher::new();
-            save_cipher_key(cipher.get_key_struct())?;
-            let data = cipher.encrypt(&data);
-            encode::from_slice(&data)
-        } else {
-            encode::from_slice(&data)
+            #[cfg(feature = "pkcs11")]
+            if let Some(uri) = &self.pkcs11_uri {
+                let token = pkcs11::Token::connect(
+                    uri,
+                    utils::token_pin().as_deref().map(|pin| pin.as_str()),
+                )?;
+                encoding
+                    .header
+                    .key_slots
+                    .push(token.seal(cipher.key(), &aad)?);
+            }
+            for secret in self.secrets()? {
+                let slot = KeySlot::seal(cipher.key(), &secret, &aad)?;
+                encoding.header.key_slots.push(slot);
+            }
+            Some(cipher)
         };
-        let path = Path::new(&output);
-        rgb.save(path)?;
-        Ok(())
+        // without stages the rows are filled straight from the mapped input
+        let (header, data) = encoding.forward(data, cipher)?;
+        let columns = self.columns(&header, data.len())?;
+        self.save(&output, &header, &data, columns, label.as_ref())
     }
 
     fn default_output(&self) -> String {
         match self.file.clone() {
+            _ if self.private || self.pad.is_some() || self.deniable => {
+                envelope::neutral_name() + ".png"
+            }
             Some(file) => file + ".png",
             None => "output.png".to_owned(),
         }
     }
 }
 
+impl Encode {
+    fn options(&self) -> file::Options {
+        file::Options {
+            encrypted: self.encrypted,
+            private: self.private,
+            compress: self.compress,
+            pad: self.pad,
+            planar: self.planar,
+        }
+    }
+
+    /// Writes the payload to the pixels of a new image, below the banner of
+    /// `label` if given, or to the carrier of a copy of the cover image.
+    fn save(
+        &self,
+        output: &str,
+        header: &Header,
+        payload: &[u8],
+        columns: usize,
+        label: Option<&Label>,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Some(cover) = &self.cover {
+            let carrier = self.carrier.unwrap_or(Carrier::Chunk);
+            if carrier == Carrier::Pixels {
+                Err("The pixels of a cover image can't carry a file, leave out --cover")?
+            }
+            let cover = utils::read_file(cover)?;
+            fs::write(output, encode::carry(&cover, carrier, header, payload)?)?;
+            return Ok(());
+        }
+        let mut encoder = Encoder::with_columns(header, payload, columns);
+        if let Some(label) = label {
+            encoder = encoder.label(label);
+        }
+        let mut rgb =
+            utils::with_progress("Encoding", |progress, cancel| encoder.run(progress, cancel))?;
+        if self.markers {
+            rgb = calibration::mark(&rgb, self.module.unwrap_or(1));
+        }
+        utils::save_png(output, &rgb, &self.png_options())?;
+        Ok(())
+    }
+
+    /// Pixel rows the header reserves for the banner of `--label`.
+    fn banner_rows(&self) -> u32 {
+        match self.label {
+            true => Label::ROWS,
+            false => 0,
+        }
+    }
+
+    fn label(&self, file_name: Option<String>, size: Option<u64>) -> Option<Label> {
+        self.label.then(|| Label {
+            file_name,
+            size,
+            date: Some(SystemTime::now()),
+        })
+    }
+
+    fn png_options(&self) -> PngOptions {
+        let defaults = PngOptions::default();
+        PngOptions {
+            compression: self.compression.unwrap_or(defaults.compression),
+            filter: self.filter.unwrap_or(defaults.filter),
+        }
+    }
+
+    /// Rows side by side in the image, planned for the profile if one is
+    /// given.
+    fn columns(&self, header: &Header, payload_len: usize) -> Result<usize, Box<dyn Error>> {
+        let Some(name) = &self.profile else {
+            return Ok(1);
+        };
+        let plan = utils::plan(name, self.profiles.as_deref(), header, payload_len)?;
+        if plan.parts.len() > 1 {
+            Err(format!(
+                "The file needs {} images for profile '{name}', but fig encode writes one",
+                plan.parts.len()
+            ))?
+        }
+        Ok(plan.columns)
+    }
+
+    /// Both layers are private envelopes, so the header only shows that the
+    /// image is deniable.
+    fn encode_deniable(
+        &self,
+        file_name: Option<String>,
+        data: &[u8],
+        output: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let header = Header {
+            encrypted: true,
+            private: true,
+            deniable: true,
+            banner_rows: self.banner_rows(),
+            ..Header::new()
+        };
+        let aad = header.associated_data();
+        let decoy = Zeroizing::new(envelope::seal(file_name, data, self.pad)?);
+        let passphrase = utils::read_passphrase(true)?;
+        let decoy = deniable::Layer {
+            passphrase: &passphrase,
+            data: &decoy,
+        };
+        let payload = if let Some(path) = &self.hidden {
+            let hidden = Zeroizing::new(utils::read_to_end(File::open(path)?)?);
+            let hidden = Zeroizing::new(envelope::seal(utils::file_name(path), &hidden, self.pad)?);
+            let hidden_passphrase = utils::read_hidden_passphrase()?;
+            let hidden = deniable::Layer {
+                passphrase: &hidden_passphrase,
+                data: &hidden,
+            };
+            deniable::seal(&decoy, Some(&hidden), &aad)?
+        } else {
+            deniable::seal(&decoy, None, &aad)?
+        };
+        self.save(
+            output,
+            &header,
+            &payload,
+            1,
+            self.label(None, None).as_ref(),
+        )
+    }
+
+    /// Secrets for the key slots of a new image, starting with the master key
+    /// or a fresh key file, which is saved whole or as Shamir shares, unless
+    /// a token key wraps the image key instead.
+    fn secrets(&self) -> Result<Vec<Secret>, Box<dyn Error>> {
+        let mut secrets = Vec::new();
+        if !self.uses_token() {
+            secrets.push(self.key_secret()?);
+        }
+        if self.passphrase {
+            secrets.push(Secret::Passphrase(utils::read_passphrase(true)?));
+        }
+        if self.recovery {
+            let code = slots::generate_recovery_code();
+            println!("Recovery code: {}", *code);
+            secrets.push(Secret::RecoveryCode(code));
+        }
+        Ok(secrets)
+    }
+
+    fn uses_token(&self) -> bool {
+        #[cfg(feature = "pkcs11")]
+        return self.pkcs11_uri.is_some();
+        #[cfg(not(feature = "pkcs11"))]
+        false
+    }
+
+    /// Shares are never stored in the keyring, as keeping the whole key
+    /// would defeat splitting it.
+    fn key_secret(&self) -> Result<Secret, Box<dyn Error>> {
+        if let Some(path) = &self.master {
+            let master = utils::load_key(path)?;
+            if !self.no_keyring {
+                Keyring::open()?.store(&master)?;
+            }
+            return Ok(Secret::MasterKey(master));
+        }
+        let key = Key::generate();
+        if let (Some(shares), Some(threshold)) = (self.shares, self.threshold) {
+            for share in encryption::split_key(&key, threshold, shares)? {
+                let path = format!("filegram-share-{}.key", share.index());
+                fs::write(path, share.to_armored().as_bytes())?;
+            }
+        } else {
+            utils::save_key("filegram.key", &key)?;
+            if !self.no_keyring {
+                Keyring::open()?.store(&key)?;
+            }
+        }
+        Ok(Secret::Key(key))
+    }
+}
+
 #[derive(Args)]
 struct Decode {
     #[arg(short, long)]
@@ -88,21 +483,70 @@ struct Decode {
     output: Option<String>,
     #[arg(short, long, help = "path to key file")]
     encrypted: Option<String>,
+    #[arg(long, conflicts_with = "encrypted", help = "unlock with a passphrase")]
+    passphrase: bool,
+    #[arg(
+        long,
+        conflicts_with_all = ["encrypted", "passphrase"],
+        help = "unlock with a recovery code"
+    )]
+    recovery: Option<String>,
+    #[arg(
+        long = "share",
+        conflicts_with_all = ["encrypted", "passphrase", "recovery"],
+        help = "path to a key share file, repeated until the threshold is met"
+    )]
+    shares: Vec<String>,
+    #[arg(
+        long,
+        conflicts_with_all = ["encrypted", "passphrase", "recovery", "shares"],
+        help = "path to the master key the image was encrypted with"
+    )]
+    master: Option<String>,
+    #[cfg(feature = "pkcs11")]
+    #[arg(
+        long,
+        conflicts_with_all = ["encrypted", "passphrase", "recovery", "shares", "master"],
+        help = "PKCS#11 URI of the token key the image key was wrapped with"
+    )]
+    pkcs11_uri: Option<String>,
 }
 
 impl CommandTrait for Decode {
     fn execute(self) -> Result<(), Box<dyn Error>> {
-        let output = self.output.clone().unwrap_or_else(|| self.default_output());
-        let file = File::open(self.file.clone())?;
-        let data = decode::from_file(BufReader::new(file))?;
-        let data = if let Some(path) = &self.encrypted {
-            let key_file = File::open(path)?;
-            let key = load_cipher_key(key_file)?;
-            let cipher = Cipher::load(&key)?;
-            cipher.decrypt(&data)
+        let input = utils::read_file(&self.file)?;
+        let (header, data) = match decode::find_carrier(&input)? {
+            Carrier::Pixels => {
+                let image = decode::image_from_file(Cursor::new(&input[..]))?;
+                let image = decode::frame_image(&image)?;
+                if let Some(header) = self.plain_header(&image)? {
+                    return self.decode_mapped(&image, header);
+                }
+                utils::with_progress("Decoding", |progress, cancel| {
+                    decode::split_header_cancellable(&image, progress, cancel)
+                })?
+            }
+            carrier => decode::from_carrier(&input, carrier)?,
+        };
+        let deniable = header.as_ref().is_some_and(|header| header.deniable);
+        let (file_name, data) = if deniable {
+            if !self.passphrase {
+                Err("Image is deniable, unlock it with --passphrase")?
+            }
+            let passphrase = utils::read_passphrase(false)?;
+            file::open_deniable(&header.unwrap_or_default(), &data, &passphrase)?
         } else {
-            data
+            let key = self.key(header.as_ref().unwrap_or(&Header::default()))?;
+            if key.is_none() && header.as_ref().is_some_and(|header| header.encrypted) {
+                Err("Image is encrypted and its key isn't in the keyring, unlock it with --encrypted, --passphrase, --recovery, --share or --master")?
+            }
+            file::open(header, data, key)?
         };
+        let output = self
+            .output
+            .clone()
+            .or_else(|| file_name.as_deref().and_then(utils::file_name))
+            .unwrap_or_else(|| self.default_output());
         fs::write(output, data)?;
         Ok(())
     }
@@ -115,15 +559,81 @@ impl CommandTrait for Decode {
     }
 }
 
-fn save_cipher_key(key: Key) -> Result<(), std::io::Error> {
-    let key_file = File::create("filegram.key")?;
-    serde_json::to_writer(key_file, &key)?;
-    Ok(())
-}
+impl Decode {
+    /// Header of an image whose payload is the file itself, with no stages
+    /// to undo and no key given for a legacy encrypted image.
+    fn plain_header(&self, image: &RgbImage) -> Result<Option<Header>, Box<dyn Error>> {
+        let header = match decode::read_header(image)? {
+            Some(header) => header,
+            None if self.unlocks() => return Ok(None),
+            None => Header::default(),
+        };
+        let plain =
+            !header.encrypted && !header.private && !header.deniable && header.stages.is_empty();
+        Ok(plain.then_some(header))
+    }
+
+    /// Whether a secret to unlock the image was given.
+    fn unlocks(&self) -> bool {
+        #[cfg(feature = "pkcs11")]
+        if self.pkcs11_uri.is_some() {
+            return true;
+        }
+        self.encrypted.is_some()
+            || self.passphrase
+            || self.recovery.is_some()
+            || !self.shares.is_empty()
+            || self.master.is_some()
+    }
 
-fn load_cipher_key(file: File) -> Result<Key, std::io::Error> {
-    let key: Key = serde_json::from_reader(file)?;
-    Ok(key)
+    /// Copies the payload straight into a memory-mapped output file.
+    fn decode_mapped(&self, image: &RgbImage, header: Header) -> Result<(), Box<dyn Error>> {
+        let output = self
+            .output
+            .clone()
+            .or_else(|| header.file_name.as_deref().and_then(utils::file_name))
+            .unwrap_or_else(|| self.default_output());
+        let mut output = mmap::create(output, decode::payload_len(image)?)?;
+        utils::with_progress("Decoding", |progress, cancel| {
+            decode::payload_into_cancellable(image, &mut output, progress, cancel)
+        })?;
+        output.flush()?;
+        Ok(())
+    }
+
+    /// Data key from the token, the given secret or the keyring.
+    fn key(&self, header: &Header) -> Result<Option<Key>, Box<dyn Error>> {
+        #[cfg(feature = "pkcs11")]
+        if let Some(uri) = &self.pkcs11_uri {
+            let token =
+                pkcs11::Token::connect(uri, utils::token_pin().as_deref().map(|pin| pin.as_str()))?;
+            return pkcs11::open(header, &token).map(Some);
+        }
+        let secret = match self.secret()? {
+            Some(secret) => Some(secret),
+            None if header.encrypted => Keyring::open()?.secret_for(header)?,
+            None => None,
+        };
+        secret
+            .map(|secret| slots::open(header, &secret))
+            .transpose()
+    }
+
+    fn secret(&self) -> Result<Option<Secret>, Box<dyn Error>> {
+        Ok(if let Some(path) = &self.encrypted {
+            Some(Secret::Key(utils::load_key(path)?))
+        } else if self.passphrase {
+            Some(Secret::Passphrase(utils::read_passphrase(false)?))
+        } else if !self.shares.is_empty() {
+            Some(Secret::Key(utils::load_shares(&self.shares)?))
+        } else if let Some(path) = &self.master {
+            Some(Secret::MasterKey(utils::load_key(path)?))
+        } else {
+            self.recovery
+                .clone()
+                .map(|code| Secret::RecoveryCode(code.into()))
+        })
+    }
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
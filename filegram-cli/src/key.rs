@@ -0,0 +1,142 @@
+use std::{error::Error, io};
+
+use clap::{Args, Subcommand};
+use filegram::encryption::{Key, KeyId};
+use zeroize::Zeroizing;
+
+use crate::{keyring::Keyring, utils, CommandTrait};
+
+#[derive(Args)]
+pub struct KeyCommand {
+    #[command(subcommand)]
+    command: KeySubcommand,
+}
+
+impl KeyCommand {
+    pub fn execute(self) -> Result<(), Box<dyn Error>> {
+        match self.command {
+            KeySubcommand::List => list(),
+            KeySubcommand::Export(export) => export.execute(),
+            KeySubcommand::Delete(delete) => delete.execute(),
+            KeySubcommand::Import(import) => import.execute(),
+            KeySubcommand::Generate(generate) => generate.execute(),
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum KeySubcommand {
+    /// List the IDs of the keys in the keyring
+    List,
+    /// Print a key file or keyring key, optionally as mnemonic words
+    Export(Export),
+    /// Remove a key from the keyring
+    Delete(Delete),
+    /// Write a key file or keyring key from mnemonic words or any supported
+    /// key format
+    Import(Import),
+    /// Write a new random key, e.g. a master key for `encode --master`
+    Generate(Generate),
+}
+
+fn list() -> Result<(), Box<dyn Error>> {
+    for id in Keyring::open()?.list()? {
+        println!("{id}");
+    }
+    Ok(())
+}
+
+#[derive(Args)]
+#[group(required = true, multiple = false, id = "source")]
+struct KeySource {
+    #[arg(short, long, help = "path to key file")]
+    file: Option<String>,
+    #[arg(long, help = "ID of a key in the keyring")]
+    id: Option<KeyId>,
+}
+
+#[derive(Args)]
+struct Export {
+    #[command(flatten)]
+    source: KeySource,
+    #[arg(short, long, help = "print the key as words that can be written down")]
+    mnemonic: bool,
+}
+
+impl Export {
+    fn execute(self) -> Result<(), Box<dyn Error>> {
+        let key = match (&self.source.file, &self.source.id) {
+            (Some(path), _) => utils::load_key(path)?,
+            (None, Some(id)) => Keyring::open()?
+                .load(id)?
+                .ok_or_else(|| format!("No key {id} in the keyring"))?,
+            (None, None) => unreachable!("clap requires a key source"),
+        };
+        if self.mnemonic {
+            println!("{}", *key.to_mnemonic());
+        } else {
+            print!("{}", *key.to_armored());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Args)]
+struct Delete {
+    #[arg(help = "ID of the key, as printed by `fig key list`")]
+    id: KeyId,
+}
+
+impl Delete {
+    fn execute(self) -> Result<(), Box<dyn Error>> {
+        Keyring::open()?.delete(&self.id)
+    }
+}
+
+#[derive(Args)]
+struct Import {
+    #[arg(short, long)]
+    output: Option<String>,
+    #[arg(long, conflicts_with = "output", help = "store the key in the keyring")]
+    keyring: bool,
+    #[arg(help = "mnemonic words, default is to read the key from stdin")]
+    words: Vec<String>,
+}
+
+impl CommandTrait for Import {
+    fn execute(self) -> Result<(), Box<dyn Error>> {
+        let output = self.output.clone().unwrap_or_else(|| self.default_output());
+        let input = if self.words.is_empty() {
+            Zeroizing::new(utils::read_to_end(io::stdin())?)
+        } else {
+            Zeroizing::new(self.words.join(" ").into_bytes())
+        };
+        let key = Key::parse(&input)?;
+        if self.keyring {
+            println!("{}", Keyring::open()?.store(&key)?);
+        } else {
+            utils::save_key(&output, &key)?;
+        }
+        Ok(())
+    }
+
+    fn default_output(&self) -> String {
+        "filegram.key".to_owned()
+    }
+}
+
+#[derive(Args)]
+struct Generate {
+    #[arg(short, long)]
+    output: String,
+}
+
+impl Generate {
+    fn execute(self) -> Result<(), Box<dyn Error>> {
+        if std::path::Path::new(&self.output).exists() {
+            Err(format!("Refusing to overwrite {}", self.output))?
+        }
+        utils::save_key(&self.output, &Key::generate())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,127 @@
+use std::{
+    env,
+    error::Error,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use filegram::{
+    encryption::{Key, KeyId},
+    header::Header,
+    keyslot::{Secret, SlotKind},
+};
+
+use crate::utils;
+
+/// Environment variable overriding the keyring directory.
+const KEYRING_VAR: &str = "FILEGRAM_KEYRING";
+
+/// Directory of key files named after their key IDs, so images can be
+/// matched to their keys through the IDs stored in their key slots.
+pub struct Keyring {
+    dir: PathBuf,
+}
+
+impl Keyring {
+    /// Opens the keyring in `$FILEGRAM_KEYRING`, or `filegram/keys` under
+    /// `$XDG_DATA_HOME` or `~/.local/share`.
+    pub fn open() -> Result<Self, Box<dyn Error>> {
+        let dir = if let Some(dir) = env::var_os(KEYRING_VAR) {
+            PathBuf::from(dir)
+        } else if let Some(data) = env::var_os("XDG_DATA_HOME").filter(|dir| !dir.is_empty()) {
+            PathBuf::from(data).join("filegram").join("keys")
+        } else if let Some(home) = env::var_os("HOME") {
+            PathBuf::from(home).join(".local/share/filegram/keys")
+        } else {
+            Err("Can't locate the keyring, set FILEGRAM_KEYRING")?
+        };
+        Ok(Keyring { dir })
+    }
+
+    fn path(&self, id: &KeyId) -> PathBuf {
+        self.dir.join(format!("{id}.key"))
+    }
+
+    pub fn store(&self, key: &Key) -> Result<KeyId, Box<dyn Error>> {
+        create_private_dir(&self.dir)?;
+        let id = key.id();
+        let mut file = create_private_file(&self.path(&id))?;
+        file.write_all(key.to_armored().as_bytes())?;
+        Ok(id)
+    }
+
+    pub fn load(&self, id: &KeyId) -> Result<Option<Key>, Box<dyn Error>> {
+        let path = self.path(id);
+        if !path.exists() {
+            return Ok(None);
+        }
+        utils::load_key(&path.to_string_lossy()).map(Some)
+    }
+
+    pub fn list(&self) -> Result<Vec<KeyId>, Box<dyn Error>> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let name = entry?.file_name();
+            let id = name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".key"))
+                .and_then(|id| id.parse::<KeyId>().ok());
+            ids.extend(id);
+        }
+        ids.sort();
+        Ok(ids)
+    }
+
+    pub fn delete(&self, id: &KeyId) -> Result<(), Box<dyn Error>> {
+        fs::remove_file(self.path(id)).map_err(|e| format!("Can't delete key {id}: {e}"))?;
+        Ok(())
+    }
+
+    /// Finds a key in the keyring that opens one of the image's key slots.
+    pub fn secret_for(&self, header: &Header) -> Result<Option<Secret>, Box<dyn Error>> {
+        for slot in &header.key_slots {
+            let Some(id) = slot.key_id() else { continue };
+            if let Some(key) = self.load(&id)? {
+                return Ok(Some(match slot.kind() {
+                    SlotKind::MasterKey => Secret::MasterKey(key),
+                    _ => Secret::Key(key),
+                }));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(unix)]
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+}
+
+#[cfg(not(unix))]
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)
+}
+
+#[cfg(unix)]
+fn create_private_file(path: &Path) -> io::Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+}
+
+#[cfg(not(unix))]
+fn create_private_file(path: &Path) -> io::Result<fs::File> {
+    fs::File::create(path)
+}
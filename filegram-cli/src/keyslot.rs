@@ -0,0 +1,283 @@
+use std::{error::Error, fs, io::Cursor};
+
+use clap::{Args, Subcommand};
+use filegram::{
+    calibration,
+    carrier::Carrier,
+    decode, encode,
+    encryption::Key,
+    header::Header,
+    keyslot::{self, KeySlot, Secret},
+};
+
+use image::RgbImage;
+
+use crate::{keyring::Keyring, utils, CommandTrait};
+
+#[derive(Args)]
+pub struct Keyslot {
+    #[command(subcommand)]
+    command: KeyslotCommand,
+}
+
+impl Keyslot {
+    pub fn execute(self) -> Result<(), Box<dyn Error>> {
+        match self.command {
+            KeyslotCommand::List(list) => list.execute(),
+            KeyslotCommand::Add(add) => add.execute(),
+            KeyslotCommand::Remove(remove) => remove.execute(),
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum KeyslotCommand {
+    /// List the key slots of an image
+    List(List),
+    /// Add a key slot, unlocking the image with an existing secret
+    Add(Add),
+    /// Remove a key slot
+    Remove(Remove),
+}
+
+/// Image whose header the slots are edited in, held by its pixels or by a
+/// carrier next to a cover image.
+enum Image {
+    Pixels(RgbImage),
+    /// frame image read through the markers drawn around it
+    Marked {
+        frame: RgbImage,
+        module: u32,
+    },
+    Carried {
+        png: Vec<u8>,
+        carrier: Carrier,
+        payload: Vec<u8>,
+    },
+}
+
+impl Image {
+    fn payload(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        match self {
+            Image::Pixels(frame) | Image::Marked { frame, .. } => {
+                Ok(decode::split_header(frame)?.1)
+            }
+            Image::Carried { payload, .. } => Ok(payload.clone()),
+        }
+    }
+
+    /// Writes the image to `path` with `header` in place of its own.
+    fn save(&self, header: &Header, path: &str) -> Result<(), Box<dyn Error>> {
+        match self {
+            Image::Pixels(image) => encode::replace_header(image, header)?.save(path)?,
+            Image::Marked { frame, module } => {
+                let frame = encode::replace_header(frame, header)?;
+                calibration::mark(&frame, *module).save(path)?
+            }
+            Image::Carried {
+                png,
+                carrier,
+                payload,
+            } => fs::write(path, encode::carry(png, *carrier, header, payload)?)?,
+        }
+        Ok(())
+    }
+}
+
+/// Frame image and header of a marked image, which is marked again once
+/// edited, so it has to be exactly as `fig encode` wrote it.
+fn marked(image: RgbImage) -> Result<(Image, Option<Header>), Box<dyn Error>> {
+    let frame = calibration::locate(&image)?;
+    let Some(module) = calibration::module(&image, &frame) else {
+        Err("Marked image was rescaled or cropped, edit the one fig encode wrote")?
+    };
+    let header = decode::read_header(&frame)?;
+    Ok((Image::Marked { frame, module }, header))
+}
+
+fn load_header(path: &str) -> Result<(Image, Header), Box<dyn Error>> {
+    // copied rather than mapped, as the output may overwrite the file
+    let png = utils::read_file(path)?.to_vec();
+    let (image, header) = match decode::find_carrier(&png)? {
+        Carrier::Pixels => {
+            let image = decode::image_from_file(Cursor::new(&png))?;
+            match decode::read_header(&image)? {
+                None if calibration::probe(&image) => marked(image)?,
+                header => (Image::Pixels(image), header),
+            }
+        }
+        carrier => {
+            let (header, payload) = decode::from_carrier(&png, carrier)?;
+            let image = Image::Carried {
+                png,
+                carrier,
+                payload,
+            };
+            (image, header)
+        }
+    };
+    match header {
+        Some(header) if header.encrypted => Ok((image, header)),
+        _ => Err("Image is not encrypted")?,
+    }
+}
+
+#[derive(Args)]
+struct List {
+    #[arg(short, long)]
+    file: String,
+}
+
+impl List {
+    fn execute(self) -> Result<(), Box<dyn Error>> {
+        let (_, header) = load_header(&self.file)?;
+        if header.key_slots.is_empty() {
+            println!("no key slots, the image opens with its original key file");
+        }
+        for (i, slot) in header.key_slots.iter().enumerate() {
+            println!("{i}: {}", slot.kind());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Args)]
+#[group(required = true, multiple = false)]
+struct Unlock {
+    #[arg(long, help = "path to a key file that opens the image")]
+    unlock_key: Option<String>,
+    #[arg(long, help = "unlock with a passphrase")]
+    unlock_passphrase: bool,
+    #[arg(long, help = "unlock with a recovery code")]
+    unlock_recovery: Option<String>,
+    #[arg(
+        long,
+        help = "path to a key share file, repeated until the threshold is met"
+    )]
+    unlock_share: Vec<String>,
+    #[arg(long, help = "path to the master key of the image")]
+    unlock_master: Option<String>,
+}
+
+impl Unlock {
+    fn secret(&self) -> Result<Secret, Box<dyn Error>> {
+        Ok(if let Some(path) = &self.unlock_key {
+            Secret::Key(utils::load_key(path)?)
+        } else if self.unlock_passphrase {
+            Secret::Passphrase(utils::read_passphrase(false)?)
+        } else if !self.unlock_share.is_empty() {
+            Secret::Key(utils::load_shares(&self.unlock_share)?)
+        } else if let Some(path) = &self.unlock_master {
+            Secret::MasterKey(utils::load_key(path)?)
+        } else {
+            let code = self.unlock_recovery.clone().unwrap_or_default();
+            Secret::RecoveryCode(code.into())
+        })
+    }
+}
+
+#[derive(Args)]
+#[group(required = true, multiple = false)]
+struct NewSecret {
+    #[arg(
+        long,
+        help = "path to write a new key file to, also kept in the keyring"
+    )]
+    key: Option<String>,
+    #[arg(long, help = "add a passphrase")]
+    passphrase: bool,
+    #[arg(long, help = "add a printed recovery code")]
+    recovery: bool,
+    #[arg(long, help = "path to an existing master key to add")]
+    master: Option<String>,
+}
+
+impl NewSecret {
+    fn secret(&self) -> Result<Secret, Box<dyn Error>> {
+        Ok(if let Some(path) = &self.key {
+            let key = Key::generate();
+            utils::save_key(path, &key)?;
+            Keyring::open()?.store(&key)?;
+            Secret::Key(key)
+        } else if self.passphrase {
+            Secret::Passphrase(utils::read_passphrase(true)?)
+        } else if let Some(path) = &self.master {
+            Secret::MasterKey(utils::load_key(path)?)
+        } else {
+            let code = keyslot::generate_recovery_code();
+            println!("Recovery code: {}", *code);
+            Secret::RecoveryCode(code)
+        })
+    }
+}
+
+#[derive(Args)]
+struct Add {
+    #[arg(short, long)]
+    file: String,
+    #[arg(short, long, help = "default is to overwrite the input image")]
+    output: Option<String>,
+    #[command(flatten)]
+    unlock: Unlock,
+    #[command(flatten)]
+    new: NewSecret,
+}
+
+impl CommandTrait for Add {
+    fn execute(self) -> Result<(), Box<dyn Error>> {
+        let output = self.output.clone().unwrap_or_else(|| self.default_output());
+        let (image, mut header) = load_header(&self.file)?;
+        let unlock = self.unlock.secret()?;
+        let data_key = keyslot::open(&header, &unlock)?;
+        let aad = header.associated_data();
+        // images made before key slots open with the key file itself,
+        // which has to keep working once slots exist; nothing checked it
+        // yet, and sealing a wrong key would lock the image for good
+        if header.key_slots.is_empty() {
+            keyslot::check_key(&header, &data_key, image.payload()?)?;
+            header
+                .key_slots
+                .push(KeySlot::seal(&data_key, &unlock, &aad)?);
+        }
+        let secret = self.new.secret()?;
+        header
+            .key_slots
+            .push(KeySlot::seal(&data_key, &secret, &aad)?);
+        image.save(&header, &output)?;
+        Ok(())
+    }
+
+    fn default_output(&self) -> String {
+        self.file.clone()
+    }
+}
+
+#[derive(Args)]
+struct Remove {
+    #[arg(short, long)]
+    file: String,
+    #[arg(short, long, help = "default is to overwrite the input image")]
+    output: Option<String>,
+    #[arg(long, help = "index of the slot, as shown by 'keyslot list'")]
+    slot: usize,
+}
+
+impl CommandTrait for Remove {
+    fn execute(self) -> Result<(), Box<dyn Error>> {
+        let output = self.output.clone().unwrap_or_else(|| self.default_output());
+        let (image, mut header) = load_header(&self.file)?;
+        if self.slot >= header.key_slots.len() {
+            Err(format!("Image has no key slot {}", self.slot))?
+        }
+        if header.key_slots.len() == 1 {
+            Err("Refusing to remove the last key slot")?
+        }
+        header.key_slots.remove(self.slot);
+        image.save(&header, &output)?;
+        Ok(())
+    }
+
+    fn default_output(&self) -> String {
+        self.file.clone()
+    }
+}
@@ -0,0 +1,96 @@
+use std::{error::Error, fs};
+
+use clap::Args;
+use filegram::{
+    encryption::{Cipher, Key},
+    file,
+    keyslot::{KeySlot, Secret},
+    padding::Bucket,
+};
+
+use crate::utils;
+
+#[derive(Args)]
+pub struct Plan {
+    /// File to plan the image of
+    file: String,
+    #[arg(long, help = "host profile the image must fit, e.g. imgur or discord")]
+    profile: String,
+    #[arg(long, help = "path to a JSON file of extra profiles")]
+    profiles: Option<String>,
+    #[arg(
+        short,
+        long,
+        help = "plan an encrypted image, as `fig encode -e` writes"
+    )]
+    encrypted: bool,
+    #[arg(
+        short,
+        long,
+        requires = "encrypted",
+        help = "as `fig encode --private`"
+    )]
+    private: bool,
+    #[arg(long, requires = "encrypted", help = "as `fig encode --pad`")]
+    pad: Option<Bucket>,
+    #[arg(long, requires = "encrypted", help = "as `fig encode --passphrase`")]
+    passphrase: bool,
+    #[arg(long, requires = "encrypted", help = "as `fig encode --recovery`")]
+    recovery: bool,
+    #[arg(short = 'z', long, help = "as `fig encode -z`")]
+    compress: bool,
+    #[arg(long, conflicts_with = "encrypted", help = "as `fig encode --planar`")]
+    planar: bool,
+}
+
+impl Plan {
+    /// Prints the layout `fig encode` would use for the file with the same
+    /// options, which shape the header and payload the same way, and every
+    /// image the file needs, of which `fig encode` writes only one.
+    pub fn execute(self) -> Result<(), Box<dyn Error>> {
+        let len = fs::metadata(&self.file)?.len();
+        let len = usize::try_from(len)?;
+        let options = file::Options {
+            encrypted: self.encrypted,
+            private: self.private,
+            compress: self.compress,
+            pad: self.pad,
+            planar: self.planar,
+        };
+        let mut encoding = options.encoding(utils::file_name(&self.file), len)?;
+        if self.encrypted {
+            // sealed slots have the same size whatever their secret, so a
+            // throwaway key file stands in for the passphrase and the code
+            let cipher = Cipher::new();
+            let slot = KeySlot::seal(cipher.key(), &Secret::Key(Key::generate()), &[])?;
+            let slots = 1 + self.passphrase as usize + self.recovery as usize;
+            encoding.header.key_slots = vec![slot; slots];
+        }
+        let payload_len = encoding.max_payload_len(len)?;
+        let plan = utils::plan(
+            &self.profile,
+            self.profiles.as_deref(),
+            &encoding.header,
+            payload_len,
+        )?;
+        println!(
+            "{} image(s) for profile '{}', {} rows side by side",
+            plan.parts.len(),
+            self.profile,
+            plan.columns
+        );
+        for (i, part) in plan.parts.iter().enumerate() {
+            println!(
+                "{}: {}x{} pixels, PNG up to {} bytes",
+                i + 1,
+                part.width,
+                part.height,
+                part.estimated_len
+            );
+        }
+        if self.compress {
+            println!("Sizes are those of a file that doesn't compress");
+        }
+        Ok(())
+    }
+}
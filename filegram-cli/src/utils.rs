@@ -1,8 +1,217 @@
-use std::io::{BufReader, Error, Read};
+use std::{
+    error::Error,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    ops::Deref,
+    path::Path,
+};
 
-pub fn read_to_end<R: Read>(reader: R) -> Result<Vec<u8>, Error> {
+use filegram::{
+    encode::{self, CompressionType, FilterType, PngOptions},
+    encryption::{self, Key, KeyShare},
+    header::Header,
+    layout::{self, Limits, Profiles},
+    mmap::{self, Mmap},
+    progress::{Cancel, Progress, Status},
+};
+use image::RgbImage;
+use indicatif::{ProgressBar, ProgressStyle};
+use zeroize::Zeroizing;
+
+/// Environment variable read instead of prompting for a passphrase.
+const PASSPHRASE_VAR: &str = "FILEGRAM_PASSPHRASE";
+/// Same for the passphrase of a hidden file.
+const HIDDEN_PASSPHRASE_VAR: &str = "FILEGRAM_HIDDEN_PASSPHRASE";
+/// PIN for PKCS#11 URIs without `pin-value` or `pin-source`.
+#[cfg(feature = "pkcs11")]
+const PKCS11_PIN_VAR: &str = "FILEGRAM_PKCS11_PIN";
+
+pub fn read_to_end<R: Read>(reader: R) -> Result<Vec<u8>, io::Error> {
     let mut buffer = BufReader::new(reader);
     let mut data = Vec::new();
     buffer.read_to_end(&mut data)?;
     Ok(data)
 }
+
+/// Contents of an input file, mapped rather than read into memory, or of
+/// stdin and other files that can't be mapped.
+pub enum Input {
+    Mapped(Mmap),
+    Read(Vec<u8>),
+}
+
+impl Deref for Input {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Input::Mapped(mmap) => mmap,
+            Input::Read(data) => data,
+        }
+    }
+}
+
+/// Reads the file at `path`, or stdin without one.
+pub fn read_input(path: Option<&str>) -> Result<Input, io::Error> {
+    match path {
+        Some(path) => read_file(path),
+        None => read_to_end(io::stdin()).map(Input::Read),
+    }
+}
+
+/// Maps the file at `path`, or reads it when it can't be mapped, like pipes
+/// and `/dev/stdin`.
+pub fn read_file(path: impl AsRef<Path>) -> Result<Input, io::Error> {
+    let path = path.as_ref();
+    if fs::metadata(path)?.is_file() {
+        if let Ok(mmap) = mmap::open(path) {
+            return Ok(Input::Mapped(mmap));
+        }
+    }
+    fs::read(path).map(Input::Read)
+}
+
+/// Runs `work` with a progress bar on stderr, which isn't drawn when stderr
+/// isn't a terminal.
+pub fn with_progress<T, E>(
+    message: &'static str,
+    work: impl FnOnce(&mut dyn Progress, &Cancel) -> Result<T, E>,
+) -> Result<T, E> {
+    let style = ProgressStyle::with_template("{msg} [{bar:40}] {bytes}/{total_bytes} ({eta})")
+        .expect("valid progress bar template")
+        .progress_chars("=> ");
+    let bar = ProgressBar::new(0).with_style(style).with_message(message);
+    let mut progress = |status: Status| {
+        bar.set_length(status.bytes_total as u64);
+        bar.set_position(status.bytes_done as u64);
+    };
+    let result = work(&mut progress, &Cancel::new());
+    bar.finish_and_clear();
+    result
+}
+
+/// Final component of `path`, so names read from images can't escape the
+/// working directory.
+pub fn file_name(path: &str) -> Option<String> {
+    Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(str::to_owned)
+}
+
+/// Limits of the built-in profile `name`, or one from the JSON file at
+/// `path`.
+pub fn load_profile(name: &str, path: Option<&str>) -> Result<Limits, Box<dyn Error>> {
+    let mut profiles = Profiles::new();
+    if let Some(path) = path {
+        profiles.load(&fs::read(path)?)?;
+    }
+    match profiles.get(name) {
+        Some(limits) => Ok(limits.clone()),
+        None => Err(format!(
+            "Unknown profile '{name}', expected one of {}",
+            profiles.names().join(", ")
+        ))?,
+    }
+}
+
+/// Layout of the images for `payload_len` bytes after `header`, shaped for
+/// the profile `name`.
+pub fn plan(
+    name: &str,
+    path: Option<&str>,
+    header: &Header,
+    payload_len: usize,
+) -> Result<layout::Plan, Box<dyn Error>> {
+    let limits = load_profile(name, path)?;
+    layout::Plan::new(payload_len, header.to_bytes().len(), &limits)
+}
+
+pub fn save_png(path: &str, image: &RgbImage, options: &PngOptions) -> Result<(), Box<dyn Error>> {
+    let mut output = BufWriter::new(File::create(path)?);
+    encode::write_png(image, &mut output, options)?;
+    output.flush()?;
+    Ok(())
+}
+
+pub fn parse_compression(s: &str) -> Result<CompressionType, String> {
+    match s {
+        "fast" => Ok(CompressionType::Fast),
+        "default" => Ok(CompressionType::Default),
+        "best" => Ok(CompressionType::Best),
+        "none" => Ok(CompressionType::Uncompressed),
+        level => match level.parse() {
+            Ok(level @ 1..=9) => Ok(CompressionType::Level(level)),
+            _ => Err(format!("Invalid compression '{s}'")),
+        },
+    }
+}
+
+pub fn parse_filter(s: &str) -> Result<FilterType, String> {
+    match s {
+        "adaptive" => Ok(FilterType::Adaptive),
+        "none" => Ok(FilterType::NoFilter),
+        "sub" => Ok(FilterType::Sub),
+        "up" => Ok(FilterType::Up),
+        "avg" => Ok(FilterType::Avg),
+        "paeth" => Ok(FilterType::Paeth),
+        _ => Err(format!("Invalid filter '{s}'")),
+    }
+}
+
+pub fn save_key(path: &str, key: &Key) -> Result<(), io::Error> {
+    fs::write(path, key.to_armored().as_bytes())
+}
+
+pub fn load_key(path: &str) -> Result<Key, Box<dyn Error>> {
+    let data = Zeroizing::new(read_to_end(File::open(path)?)?);
+    Key::parse(&data)
+}
+
+pub fn load_shares(paths: &[String]) -> Result<Key, Box<dyn Error>> {
+    let shares = paths
+        .iter()
+        .map(|path| {
+            let data = Zeroizing::new(read_to_end(File::open(path)?)?);
+            KeyShare::parse(&data)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    encryption::combine_shares(&shares)
+}
+
+pub fn read_passphrase(confirm: bool) -> Result<Zeroizing<String>, Box<dyn Error>> {
+    prompt_passphrase(PASSPHRASE_VAR, "Passphrase", confirm)
+}
+
+pub fn read_hidden_passphrase() -> Result<Zeroizing<String>, Box<dyn Error>> {
+    prompt_passphrase(HIDDEN_PASSPHRASE_VAR, "Hidden passphrase", true)
+}
+
+fn prompt_passphrase(
+    var: &str,
+    prompt: &str,
+    confirm: bool,
+) -> Result<Zeroizing<String>, Box<dyn Error>> {
+    if let Ok(passphrase) = std::env::var(var) {
+        return Ok(Zeroizing::new(passphrase));
+    }
+    let passphrase = Zeroizing::new(rpassword::prompt_password(format!("{prompt}: "))?);
+    if confirm {
+        let repeated = Zeroizing::new(rpassword::prompt_password(format!(
+            "Repeat {}: ",
+            prompt.to_lowercase()
+        ))?);
+        if passphrase != repeated {
+            Err("Passphrases don't match")?
+        }
+    }
+    if passphrase.is_empty() {
+        Err("Empty passphrase")?
+    }
+    Ok(passphrase)
+}
+
+#[cfg(feature = "pkcs11")]
+pub fn token_pin() -> Option<Zeroizing<String>> {
+    std::env::var(PKCS11_PIN_VAR).ok().map(Zeroizing::new)
+}
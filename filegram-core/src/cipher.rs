@@ -0,0 +1,67 @@
+//! ChaCha20-Poly1305 encryption of payloads, as written by `filegram`
+//! with a key file.
+
+use alloc::vec::Vec;
+
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305,
+};
+
+use crate::Error;
+
+pub const KEY_SIZE: usize = 32;
+pub const NONCE_SIZE: usize = 12;
+/// Poly1305 tag appended by the cipher
+pub const TAG_SIZE: usize = 16;
+
+pub struct Cipher {
+    cipher: ChaCha20Poly1305,
+    nonce: [u8; NONCE_SIZE],
+}
+
+impl Cipher {
+    pub fn new(key: &[u8; KEY_SIZE], nonce: &[u8; NONCE_SIZE]) -> Self {
+        Cipher {
+            cipher: ChaCha20Poly1305::new(key.into()),
+            nonce: *nonce,
+        }
+    }
+
+    /// Encrypts `buf`, authenticating it together with the associated data
+    /// `aad`, the header bytes without key slots.
+    pub fn encrypt(&self, buf: &[u8], aad: &[u8]) -> Vec<u8> {
+        let payload = Payload { msg: buf, aad };
+        self.cipher
+            .encrypt(&self.nonce.into(), payload)
+            .expect("payload within the ChaCha20-Poly1305 size limit")
+    }
+
+    /// Decrypts `buf`, failing if it or the associated data `aad` were
+    /// modified, or the key is wrong.
+    pub fn decrypt(&self, buf: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+        let payload = Payload { msg: buf, aad };
+        self.cipher
+            .decrypt(&self.nonce.into(), payload)
+            .map_err(|_| Error::Authentication)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cipher_test() {
+        let cipher = Cipher::new(&[7; KEY_SIZE], &[1; NONCE_SIZE]);
+        let encrypted = cipher.encrypt(b"filegram", b"header");
+        assert_eq!(
+            b"filegram".as_slice(),
+            cipher.decrypt(&encrypted, b"header").unwrap()
+        );
+        assert_eq!(
+            Err(Error::Authentication),
+            cipher.decrypt(&encrypted, b"other")
+        );
+    }
+}
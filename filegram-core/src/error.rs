@@ -0,0 +1,28 @@
+use core::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    MissingHeader,
+    TruncatedHeader,
+    TruncatedImage,
+    Padding,
+    /// The pixel buffer doesn't match the size of the frame.
+    BufferSize,
+    Authentication,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            Error::MissingHeader => "Missing filegram header",
+            Error::TruncatedHeader => "Truncated header",
+            Error::TruncatedImage => "Truncated image",
+            Error::Padding => "Invalid padding of the last row",
+            Error::BufferSize => "Pixel buffer doesn't match the frame size",
+            Error::Authentication => "Authentication failed: wrong key or modified image",
+        };
+        f.write_str(message)
+    }
+}
+
+impl core::error::Error for Error {}
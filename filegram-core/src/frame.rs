@@ -0,0 +1,158 @@
+//! Frames in a raw RGB8 pixel buffer: the header, zero-padded to whole rows,
+//! then the payload, whose last row is always padded, even if it holds no
+//! data.
+
+use crate::{
+    header,
+    padding::{pad_block, unpad_block},
+    Error, BUFFER_SIZE,
+};
+
+/// Rows of a frame with a header of `header_len` bytes and a payload of
+/// `payload_len` bytes.
+pub fn height(header_len: usize, payload_len: usize) -> usize {
+    header_len.div_ceil(BUFFER_SIZE) + (payload_len / BUFFER_SIZE) + 1
+}
+
+/// Bytes of `data` that go to row `row`, empty past its end.
+pub fn row_data(data: &[u8], row: usize) -> &[u8] {
+    let start = (row * BUFFER_SIZE).min(data.len());
+    &data[start..(start + BUFFER_SIZE).min(data.len())]
+}
+
+/// Copies `bytes` to the start of a payload row, padding the row in place
+/// when they don't fill it.
+pub fn write_row(row: &mut [u8], bytes: &[u8]) {
+    row[..bytes.len()].copy_from_slice(bytes);
+    if bytes.len() < BUFFER_SIZE {
+        pad_block(row, bytes.len());
+    }
+}
+
+/// Writes a frame to `pixels`, which must be [`height`] rows long.
+pub fn write(pixels: &mut [u8], header: &[u8], payload: &[u8]) -> Result<(), Error> {
+    if pixels.len() != height(header.len(), payload.len()) * BUFFER_SIZE {
+        return Err(Error::BufferSize);
+    }
+    let (header_rows, payload_rows) =
+        pixels.split_at_mut(header.len().div_ceil(BUFFER_SIZE) * BUFFER_SIZE);
+    header_rows[..header.len()].copy_from_slice(header);
+    header_rows[header.len()..].fill(0);
+    for (i, row) in payload_rows.chunks_mut(BUFFER_SIZE).enumerate() {
+        write_row(row, row_data(payload, i));
+    }
+    Ok(())
+}
+
+/// Rows of a frame one at a time, for devices without the memory for the
+/// whole pixel buffer.
+pub fn rows<'a>(
+    header: &'a [u8],
+    payload: &'a [u8],
+) -> impl Iterator<Item = [u8; BUFFER_SIZE]> + 'a {
+    let header_rows = (0..header.len().div_ceil(BUFFER_SIZE)).map(|i| {
+        let mut row = [0u8; BUFFER_SIZE];
+        let bytes = row_data(header, i);
+        row[..bytes.len()].copy_from_slice(bytes);
+        row
+    });
+    let payload_rows = (0..height(0, payload.len())).map(|i| {
+        let mut row = [0u8; BUFFER_SIZE];
+        write_row(&mut row, row_data(payload, i));
+        row
+    });
+    header_rows.chain(payload_rows)
+}
+
+/// Data of the payload rows, without the padding of the last one.
+pub fn payload(rows: &[u8]) -> Result<&[u8], Error> {
+    if rows.is_empty() {
+        return Ok(rows);
+    }
+    let last = rows.len().checked_sub(BUFFER_SIZE).ok_or(Error::Padding)?;
+    let tail = unpad_block(&rows[last..])?;
+    // the data of the last row is followed by its padding
+    Ok(&rows[..last + tail.len()])
+}
+
+/// Frame rows of an image whose pixel rows each hold `columns` of them,
+/// without the zeroed rows after the payload that fill out its last pixel
+/// row. The padded last row of the payload never ends with a zero.
+pub fn trim_fill(pixels: &[u8], columns: usize) -> &[u8] {
+    let mut end = pixels.len() - pixels.len() % BUFFER_SIZE;
+    for _ in 1..columns {
+        if end < BUFFER_SIZE || pixels[end - 1] != 0 {
+            break;
+        }
+        end -= BUFFER_SIZE;
+    }
+    &pixels[..end]
+}
+
+/// Bytes taken by the header rows at the start of `pixels`, zero for frames
+/// without a header.
+pub fn header_len(pixels: &[u8]) -> Result<usize, Error> {
+    let first_row = &pixels[..BUFFER_SIZE.min(pixels.len())];
+    if !header::is_present(first_row) {
+        return Ok(0);
+    }
+    let len = header::encoded_len(first_row)?.div_ceil(BUFFER_SIZE) * BUFFER_SIZE;
+    if len >= pixels.len() {
+        return Err(Error::TruncatedImage);
+    }
+    Ok(len)
+}
+
+/// Splits a frame into its header rows, empty when it has none, and its
+/// payload. The rows of a label banner, recorded in a header field this
+/// crate doesn't parse, stay at the start of the payload.
+pub fn split(pixels: &[u8]) -> Result<(&[u8], &[u8]), Error> {
+    if !pixels.len().is_multiple_of(BUFFER_SIZE) {
+        return Err(Error::BufferSize);
+    }
+    let (header, rows) = pixels.split_at(header_len(pixels)?);
+    Ok((header, payload(rows)?))
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec;
+
+    use super::*;
+    use crate::header::{MAGIC, PREFIX_SIZE};
+
+    #[test]
+    fn frame_test() {
+        let mut header = MAGIC.to_vec();
+        header.extend([1, 0, 0, 0, 0, 2, 0xaa, 0xbb]);
+        let payload: vec::Vec<u8> = (0..BUFFER_SIZE * 2).map(|i| i as u8).collect();
+
+        let height = height(header.len(), payload.len());
+        assert_eq!(4, height);
+        let mut pixels = vec![0xffu8; height * BUFFER_SIZE];
+        write(&mut pixels, &header, &payload).unwrap();
+        assert_eq!(
+            pixels,
+            rows(&header, &payload).flatten().collect::<vec::Vec<_>>()
+        );
+        assert_eq!(
+            Err(Error::BufferSize),
+            write(&mut pixels[1..], &header, &payload)
+        );
+
+        let (header_rows, data) = split(&pixels).unwrap();
+        assert_eq!(PREFIX_SIZE + 2, header::encoded_len(header_rows).unwrap());
+        assert_eq!(BUFFER_SIZE, header_rows.len());
+        assert_eq!(payload, data);
+        assert_eq!(
+            (&[][..], &payload[..]),
+            split(&pixels[BUFFER_SIZE..]).unwrap()
+        );
+        assert_eq!(Err(Error::TruncatedImage), split(&pixels[..BUFFER_SIZE]));
+
+        let mut wide = pixels.clone();
+        wide.resize(height.div_ceil(3) * 3 * BUFFER_SIZE, 0);
+        assert_eq!(pixels, trim_fill(&wide, 3));
+        assert_eq!(pixels, trim_fill(&pixels, 3));
+    }
+}
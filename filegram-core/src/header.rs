@@ -0,0 +1,32 @@
+//! Fixed size prefix of the header stored in the first rows of a frame. The
+//! fields after it are parsed by the `filegram` crate.
+
+use crate::Error;
+
+/// Marks images that start with a header row.
+pub const MAGIC: [u8; 4] = *b"FGRM";
+pub const FORMAT_VERSION: u8 = 1;
+
+/// magic, version, flags and fields length
+pub const PREFIX_SIZE: usize = MAGIC.len() + 1 + 1 + 4;
+
+pub fn is_present(data: &[u8]) -> bool {
+    data.starts_with(&MAGIC)
+}
+
+/// Number of bytes taken by the header starting `data`, which only needs
+/// to hold the fixed size prefix.
+pub fn encoded_len(data: &[u8]) -> Result<usize, Error> {
+    if !is_present(data) {
+        return Err(Error::MissingHeader);
+    }
+    let Some(len) = data.get(PREFIX_SIZE - 4..PREFIX_SIZE) else {
+        return Err(Error::TruncatedHeader);
+    };
+    let len = u32::from_be_bytes(len.try_into().expect("4 bytes"));
+    // a crafted length can overflow the address space of 32 bit targets
+    usize::try_from(len)
+        .ok()
+        .and_then(|len| PREFIX_SIZE.checked_add(len))
+        .ok_or(Error::TruncatedHeader)
+}
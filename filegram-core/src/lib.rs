@@ -0,0 +1,24 @@
+//! Filegram frame layout on raw RGB8 pixel buffers, without `std`.
+//!
+//! Each row of a frame is [`IMAGE_WIDTH`] pixels, holding [`BUFFER_SIZE`]
+//! bytes. [`frame`] writes and reads them, `png` and `cipher` are optional
+//! features.
+#![no_std]
+
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+
+#[cfg(feature = "cipher")]
+pub mod cipher;
+mod error;
+pub mod frame;
+pub mod header;
+pub mod padding;
+#[cfg(feature = "png")]
+pub mod png;
+
+pub use error::Error;
+
+pub const IMAGE_WIDTH: usize = 85;
+pub const BUFFER_SIZE: usize = IMAGE_WIDTH * 3;
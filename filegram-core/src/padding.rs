@@ -0,0 +1,33 @@
+use block_padding::{
+    generic_array::{typenum::U255, GenericArray},
+    AnsiX923, Padding,
+};
+
+use crate::{Error, BUFFER_SIZE};
+
+/// Pads the last row of a frame in place, after its first `data_len` bytes.
+pub fn pad_block(block: &mut [u8], data_len: usize) {
+    AnsiX923::pad(GenericArray::<u8, U255>::from_mut_slice(block), data_len);
+}
+
+/// Data bytes of the padded last row of a frame.
+pub fn unpad_block(block: &[u8]) -> Result<&[u8], Error> {
+    if block.len() != BUFFER_SIZE {
+        return Err(Error::Padding);
+    }
+    AnsiX923::unpad(GenericArray::<u8, U255>::from_slice(block)).map_err(|_| Error::Padding)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn padding_test() {
+        let mut block = [0xffu8; BUFFER_SIZE];
+        pad_block(&mut block, 3);
+        assert_eq!(&[0xff; 3], unpad_block(&block).unwrap());
+        assert_eq!(BUFFER_SIZE as u8 - 3, block[BUFFER_SIZE - 1]);
+        assert_eq!(Err(Error::Padding), unpad_block(&block[1..]));
+    }
+}
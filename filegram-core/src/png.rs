@@ -0,0 +1,59 @@
+//! Frames as PNG files.
+
+use std::{
+    io::{self, BufRead, Seek, Write},
+    vec::Vec,
+};
+
+use png::{BitDepth, ColorType, Decoder, DecodingError, Encoder, EncodingError};
+
+use crate::{BUFFER_SIZE, IMAGE_WIDTH};
+
+/// Writes the pixels of a frame as a PNG file.
+pub fn write(output: impl Write, pixels: &[u8]) -> Result<(), EncodingError> {
+    let height = pixels.len() / BUFFER_SIZE;
+    let mut encoder = Encoder::new(output, IMAGE_WIDTH as u32, height as u32);
+    encoder.set_color(ColorType::Rgb);
+    encoder.set_depth(BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pixels[..height * BUFFER_SIZE])?;
+    writer.finish()
+}
+
+/// Reads the pixels of a frame from a PNG file.
+pub fn read(input: impl BufRead + Seek) -> Result<Vec<u8>, DecodingError> {
+    let mut reader = Decoder::new(input).read_info()?;
+    let info = reader.info();
+    if info.width as usize != IMAGE_WIDTH
+        || info.color_type != ColorType::Rgb
+        || info.bit_depth != BitDepth::Eight
+    {
+        Err(DecodingError::IoError(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "frame must be RGB and 85 pixels wide",
+        )))?
+    }
+    let mut pixels =
+        std::vec![0u8; reader.output_buffer_size().ok_or(DecodingError::LimitsExceeded)?];
+    reader.next_frame(&mut pixels)?;
+    Ok(pixels)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::frame;
+
+    #[test]
+    fn png_test() {
+        let payload = b"filegram";
+        let mut pixels = std::vec![0u8; frame::height(0, payload.len()) * BUFFER_SIZE];
+        frame::write(&mut pixels, &[], payload).unwrap();
+
+        let mut file = Vec::new();
+        write(&mut file, &pixels).unwrap();
+        assert_eq!(pixels, read(Cursor::new(file)).unwrap());
+    }
+}
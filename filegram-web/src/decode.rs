@@ -1,13 +1,16 @@
-use base64::{engine::general_purpose, Engine};
 use filegram::{
-    decode,
-    encryption::{Cipher, Key},
+    decode::{self, Decoder},
+    encryption::Key,
+    file,
+    header::Header,
+    keyslot::{self, Secret},
 };
 use gloo_file::{callbacks::FileReader, Blob, File, ObjectUrl};
+use gloo_timers::callback::Timeout;
 use gloo_utils::document;
-use std::collections::HashMap;
+use std::{collections::HashMap, error::Error};
 use wasm_bindgen::JsCast;
-use web_sys::{Event, HtmlElement, HtmlInputElement};
+use web_sys::{Event, HtmlElement, HtmlInputElement, HtmlTextAreaElement};
 use yew::prelude::*;
 
 use crate::utils;
@@ -15,18 +18,33 @@ use crate::utils;
 type FileName = String;
 type Data = Vec<u8>;
 
+/// Rows read between two redraws of the progress indicator.
+const ROWS_PER_STEP: usize = 4096;
+
 pub enum Msg {
-    Key(Option<Key>),
+    Key(Option<Secret>),
     Decrypt(bool),
-    LoadedBytes(FileName, Vec<u8>),
+    LoadedBytes(FileName, Result<Vec<u8>, String>),
     Files(Vec<File>),
+    Step,
+    Cancel,
+}
+
+/// Image being decoded a few rows at a time, so the page stays responsive.
+struct Job {
+    file_name: FileName,
+    decoder: Decoder,
+    /// Dropping the timeout when the job is cancelled stops the next step.
+    next_step: Option<Timeout>,
 }
 
 pub struct DecodeComponent {
     files: Vec<(FileName, Data)>,
     readers: HashMap<FileName, FileReader>,
     hide_key_input: bool,
-    key: Option<Key>,
+    key: Option<Secret>,
+    error: Option<String>,
+    job: Option<Job>,
 }
 
 impl Component for DecodeComponent {
@@ -39,6 +57,8 @@ impl Component for DecodeComponent {
             readers: HashMap::default(),
             hide_key_input: true,
             key: None,
+            error: None,
+            job: None,
         }
     }
 
@@ -58,14 +78,9 @@ impl Component for DecodeComponent {
         });
 
         let on_input = ctx.link().callback(move |e: InputEvent| {
-            let key_ref: HtmlInputElement = e.target_unchecked_into();
+            let key_ref: HtmlTextAreaElement = e.target_unchecked_into();
             let key = key_ref.value();
-            let key = general_purpose::STANDARD_NO_PAD.decode(key).unwrap();
-            if let Ok(key) = serde_json::from_slice(&key) {
-                Msg::Key(Some(key))
-            } else {
-                Msg::Key(None)
-            }
+            Msg::Key(Key::parse(key.as_bytes()).ok().map(Secret::Key))
         });
 
         let on_check = ctx.link().callback(move |e: MouseEvent| {
@@ -84,7 +99,7 @@ impl Component for DecodeComponent {
                         <input type="checkbox" id="decrypt" onclick={on_check}/>
                         <span class="checkmark"></span>
                     </label>
-                    <input type="text" placeholder={"Key string"} hidden={self.hide_key_input} oninput={on_input}/>
+                    <textarea placeholder={"Key file contents or mnemonic words"} hidden={self.hide_key_input} oninput={on_input}/>
                 </div>
                 <div>
                     <label class="custom-file-upload">
@@ -92,6 +107,10 @@ impl Component for DecodeComponent {
                         <input type="file" accept="image/png" onchange={on_change} multiple=false/>
                     </label>
                 </div>
+                { self.view_job(ctx) }
+                if let Some(error) = &self.error {
+                    <p class="error">{error}</p>
+                }
                 <div>
                 { for self.files.iter().rev().map(|(n,d)| Self::view_file(n,d))}
                 </div>
@@ -111,7 +130,7 @@ impl Component for DecodeComponent {
                         gloo_file::callbacks::read_as_bytes(&file, move |res| {
                             link.send_message(Msg::LoadedBytes(
                                 file_name,
-                                res.expect("failed to read file"),
+                                res.map_err(|e| e.to_string()),
                             ))
                         })
                     };
@@ -120,17 +139,44 @@ impl Component for DecodeComponent {
                 true
             }
             Msg::LoadedBytes(file_name, data) => {
-                let data = Self::decode(data);
-                let file_contents = if let Some(key) = &self.key {
-                    let cipher = Cipher::load(key).unwrap();
-                    cipher.decrypt(&data)
-                } else {
-                    data
-                };
-                self.files.push((file_name.clone(), file_contents));
                 self.readers.remove(&file_name);
+                match data.map_err(Into::into).and_then(Self::decoder) {
+                    Ok(decoder) => {
+                        self.job = Some(Job {
+                            file_name,
+                            decoder,
+                            next_step: None,
+                        });
+                        ctx.link().send_message(Msg::Step);
+                    }
+                    Err(e) => self.error = Some(format!("Couldn't decode {file_name}: {e}")),
+                }
+                true
+            }
+            Msg::Step => {
+                let Some(job) = &mut self.job else {
+                    return false;
+                };
+                if job.decoder.step(ROWS_PER_STEP) {
+                    let job = self.job.take().unwrap();
+                    let (header, data) = job.decoder.into_parts();
+                    match self.open(header, data) {
+                        Ok((name, data)) => {
+                            self.files.push((name.unwrap_or(job.file_name), data));
+                            self.error = None;
+                        }
+                        Err(e) => {
+                            self.error = Some(format!("Couldn't decode {}: {e}", job.file_name))
+                        }
+                    }
+                } else {
+                    // yield to the browser so it can redraw before the next step
+                    let link = ctx.link().clone();
+                    job.next_step = Some(Timeout::new(0, move || link.send_message(Msg::Step)));
+                }
                 true
             }
+            Msg::Cancel => self.job.take().is_some(),
             Msg::Key(key) => {
                 self.key = key;
                 false
@@ -182,8 +228,40 @@ impl DecodeComponent {
         download_element.dyn_into::<HtmlElement>().unwrap().click();
     }
 
-    fn decode(data: Vec<u8>) -> Vec<u8> {
+    fn view_job(&self, ctx: &Context<Self>) -> Html {
+        let Some(job) = &self.job else {
+            return html! {};
+        };
+        let status = job.decoder.status();
+        let on_cancel = ctx.link().callback(|_| Msg::Cancel);
+        html! {
+            <div class="progress">
+                <p>{format!("Decoding {}", job.file_name)}</p>
+                <progress value={status.rows_done.to_string()} max={status.rows_total.to_string()}/>
+                <button onclick={on_cancel}>{"Cancel"}</button>
+            </div>
+        }
+    }
+
+    /// Decodes the header and payload of an image into its file name, if it
+    /// has one, and contents.
+    fn open(
+        &self,
+        header: Option<Header>,
+        data: Vec<u8>,
+    ) -> Result<(Option<FileName>, Data), Box<dyn Error>> {
+        let key = match &self.key {
+            Some(secret) => Some(keyslot::open(
+                header.as_ref().unwrap_or(&Header::default()),
+                secret,
+            )?),
+            None => None,
+        };
+        file::open(header, data, key)
+    }
+
+    fn decoder(data: Vec<u8>) -> Result<Decoder, Box<dyn Error>> {
         let cursor = std::io::Cursor::new(data);
-        decode::from_file(cursor).unwrap()
+        Decoder::new(decode::image_from_file(cursor)?)
     }
 }
@@ -1,11 +1,13 @@
 use base64::{engine::general_purpose, Engine as _};
-use filegram::encode;
+use filegram::encode::{self, CompressionType, Encoder, FilterType, PngOptions};
 use filegram::encryption::{Cipher, Key};
+use filegram::file;
 use gloo_file::{callbacks::FileReader, File};
 use gloo_file::{Blob, ObjectUrl};
+use gloo_timers::callback::Timeout;
 use gloo_utils::document;
+use image::RgbImage;
 use std::collections::HashMap;
-use std::io::{Read, Seek, SeekFrom};
 use wasm_bindgen::JsCast;
 use web_sys::{Event, HtmlElement, HtmlInputElement};
 use yew::prelude::*;
@@ -13,15 +15,41 @@ use yew::prelude::*;
 type FileName = String;
 type Data = Vec<u8>;
 
+/// Rows written between two redraws of the progress indicator.
+const ROWS_PER_STEP: usize = 256;
+
 pub enum Msg {
-    LoadedBytes(FileName, Vec<u8>, bool),
-    Files(Vec<File>, bool),
+    LoadedBytes(FileName, Result<Vec<u8>, String>, Options),
+    Files(Vec<File>, Options),
+    Step,
+    Cancel,
+}
+
+/// Choices made on the form when the file was selected.
+#[derive(Clone, Copy)]
+pub struct Options {
+    encrypt: bool,
+    /// Slower PNG compression without filters, which suits unencrypted text.
+    compact: bool,
+}
+
+/// Image being encoded a few rows at a time, so the page stays responsive.
+struct Job {
+    file_name: FileName,
+    encoder: Encoder<'static>,
+    key: Option<Key>,
+    compact: bool,
+    /// Dropping the timeout when the job is cancelled stops the next step.
+    next_step: Option<Timeout>,
 }
 
 pub struct EncodeComponent {
     encrypt_ref: NodeRef,
+    compact_ref: NodeRef,
     files: Vec<(FileName, Data, Option<Key>)>,
     readers: HashMap<FileName, FileReader>,
+    job: Option<Job>,
+    error: Option<String>,
 }
 
 impl Component for EncodeComponent {
@@ -31,13 +59,17 @@ impl Component for EncodeComponent {
     fn create(_ctx: &Context<Self>) -> Self {
         Self {
             encrypt_ref: NodeRef::default(),
+            compact_ref: NodeRef::default(),
             files: Vec::new(),
             readers: HashMap::default(),
+            job: None,
+            error: None,
         }
     }
 
     fn view(&self, ctx: &Context<Self>) -> Html {
         let encrypt_ref = self.encrypt_ref.clone();
+        let compact_ref = self.compact_ref.clone();
         let on_change = ctx.link().callback(move |e: Event| {
             let mut selected_files = Vec::new();
             let input: HtmlInputElement = e.target_unchecked_into();
@@ -49,8 +81,12 @@ impl Component for EncodeComponent {
                     .map(File::from);
                 selected_files.extend(files);
             }
-            let encrypt = encrypt_ref.cast::<HtmlInputElement>().unwrap().checked();
-            Msg::Files(selected_files, encrypt)
+            let checked = |node: &NodeRef| node.cast::<HtmlInputElement>().unwrap().checked();
+            let options = Options {
+                encrypt: checked(&encrypt_ref),
+                compact: checked(&compact_ref),
+            };
+            Msg::Files(selected_files, options)
         });
 
         html! {
@@ -65,12 +101,23 @@ impl Component for EncodeComponent {
                         <span class="checkmark"></span>
                     </label>
                 </div>
+                <div>
+                    <label class="container" for="compact">
+                        {"Smaller image (slower)"}
+                        <input type="checkbox" id="compact" ref={self.compact_ref.clone()}/>
+                        <span class="checkmark"></span>
+                    </label>
+                </div>
                 <div>
                     <label class="custom-file-upload">
                         {"Select file"}
                         <input type="file" onchange={on_change} multiple=false/>
                     </label>
                 </div>
+                { self.view_job(ctx) }
+                if let Some(error) = &self.error {
+                    <p class="error">{error}</p>
+                }
                 <div>
                 { for self.files.iter().rev().map(|(n,d,k)| Self::view_file(n,d,k))}
                 </div>
@@ -80,7 +127,7 @@ impl Component for EncodeComponent {
 
     fn update(&mut self, ctx: &Context<Self>, msg: Self::Message) -> bool {
         match msg {
-            Msg::Files(files, encrypt) => {
+            Msg::Files(files, options) => {
                 for file in files.into_iter() {
                     let file_name = file.name();
                     let task = {
@@ -90,8 +137,8 @@ impl Component for EncodeComponent {
                         gloo_file::callbacks::read_as_bytes(&file, move |res| {
                             link.send_message(Msg::LoadedBytes(
                                 file_name,
-                                res.expect("failed to read file"),
-                                encrypt,
+                                res.map_err(|e| e.to_string()),
+                                options,
                             ))
                         })
                     };
@@ -99,18 +146,59 @@ impl Component for EncodeComponent {
                 }
                 true
             }
-            Msg::LoadedBytes(file_name, data, encrypt) => {
-                let (image, key) = if encrypt {
-                    let cipher = Cipher::new();
-                    let data = cipher.encrypt(&data);
-                    (Self::encode(data), Some(cipher.get_key_struct()))
-                } else {
-                    (Self::encode(data), None)
-                };
-                self.files.push((file_name.clone(), image, key));
+            Msg::LoadedBytes(file_name, data, options) => {
                 self.readers.remove(&file_name);
+                let data = match data {
+                    Ok(data) => data,
+                    Err(e) => {
+                        self.error = Some(format!("Couldn't read {file_name}: {e}"));
+                        return true;
+                    }
+                };
+                let key = options.encrypt.then(Key::generate);
+                let encoded = file::Options {
+                    encrypted: options.encrypt,
+                    ..file::Options::default()
+                }
+                .encoding(Some(file_name.clone()), data.len())
+                .and_then(|encoding| {
+                    let plaintext = encoding.plaintext(&data)?;
+                    encoding.forward(plaintext, key.as_ref().map(Cipher::load))
+                });
+                let (header, data) = match encoded {
+                    Ok((header, payload)) => (header, payload.into_owned()),
+                    Err(e) => {
+                        self.error = Some(format!("Couldn't encode {file_name}: {e}"));
+                        return true;
+                    }
+                };
+                self.error = None;
+                self.job = Some(Job {
+                    file_name,
+                    encoder: Encoder::with_header(&header, data),
+                    key,
+                    compact: options.compact,
+                    next_step: None,
+                });
+                ctx.link().send_message(Msg::Step);
                 true
             }
+            Msg::Step => {
+                let Some(job) = &mut self.job else {
+                    return false;
+                };
+                if job.encoder.step(ROWS_PER_STEP) {
+                    let job = self.job.take().unwrap();
+                    let image = Self::encode(job.encoder.into_image(), job.compact);
+                    self.files.push((job.file_name, image, job.key));
+                } else {
+                    // yield to the browser so it can redraw before the next step
+                    let link = ctx.link().clone();
+                    job.next_step = Some(Timeout::new(0, move || link.send_message(Msg::Step)));
+                }
+                true
+            }
+            Msg::Cancel => self.job.take().is_some(),
         }
     }
 }
@@ -126,8 +214,7 @@ impl EncodeComponent {
 
         let (on_click, donwload_text) = if let Some(key) = key {
             let key_file_name = name.to_owned() + ".key";
-            let key_json = serde_json::to_string(key).unwrap();
-            let key_data = general_purpose::STANDARD_NO_PAD.encode(key_json);
+            let key_data = key.to_armored();
             let key_blob = Blob::new(key_data.as_str());
             let key_blob_url = ObjectUrl::from(key_blob);
             let on_click = Callback::from(move |_| {
@@ -167,16 +254,31 @@ impl EncodeComponent {
         download_element.dyn_into::<HtmlElement>().unwrap().click();
     }
 
-    fn encode(data: Vec<u8>) -> Vec<u8> {
-        let img = encode::from_slice(&data);
-
-        let mut cursor = std::io::Cursor::new(Vec::new());
-        img.write_to(&mut cursor, image::ImageFormat::Png).unwrap();
+    fn view_job(&self, ctx: &Context<Self>) -> Html {
+        let Some(job) = &self.job else {
+            return html! {};
+        };
+        let status = job.encoder.status();
+        let on_cancel = ctx.link().callback(|_| Msg::Cancel);
+        html! {
+            <div class="progress">
+                <p>{format!("Encoding {}", job.file_name)}</p>
+                <progress value={status.rows_done.to_string()} max={status.rows_total.to_string()}/>
+                <button onclick={on_cancel}>{"Cancel"}</button>
+            </div>
+        }
+    }
 
-        cursor.seek(SeekFrom::Start(0)).unwrap();
+    fn encode(img: RgbImage, compact: bool) -> Vec<u8> {
+        let options = match compact {
+            true => PngOptions {
+                compression: CompressionType::Default,
+                filter: FilterType::NoFilter,
+            },
+            false => PngOptions::default(),
+        };
         let mut out = Vec::new();
-        cursor.read_to_end(&mut out).unwrap();
-
+        encode::write_png(&img, &mut out, &options).unwrap();
         out
     }
 }
@@ -0,0 +1,47 @@
+//! Encode and decode throughput on 1 GiB of data. The serial and parallel
+//! paths are compared by running
+//!
+//! ```sh
+//! cargo bench -p filegram --bench codec
+//! cargo bench -p filegram --bench codec --features parallel
+//! ```
+//!
+//! `FILEGRAM_BENCH_SIZE` sets another input size, in bytes.
+
+use std::env;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use filegram::{decode, encode, header::Header};
+
+const GIB: usize = 1 << 30;
+
+fn input_size() -> usize {
+    env::var("FILEGRAM_BENCH_SIZE")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(GIB)
+}
+
+fn codec(c: &mut Criterion) {
+    let size = input_size();
+    let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+    let header = Header::new();
+
+    let mut group = c.benchmark_group("codec");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(size as u64));
+    group.bench_function("encode::from_reader", |b| {
+        b.iter(|| encode::from_reader(&mut &data[..], size).unwrap())
+    });
+    group.bench_function("encode::with_header", |b| {
+        b.iter(|| encode::with_header(&header, &data))
+    });
+    let image = encode::with_header(&header, &data);
+    group.bench_function("decode::split_header", |b| {
+        b.iter(|| decode::split_header(&image).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, codec);
+criterion_main!(benches);
@@ -0,0 +1,117 @@
+use std::error::Error;
+
+use base64::{engine::general_purpose, Engine};
+use zeroize::Zeroizing;
+
+const BEGIN: &str = "-----BEGIN ";
+const END: &str = "-----END ";
+const DASHES: &str = "-----";
+const LINE_WIDTH: usize = 64;
+
+const CRC24_INIT: u32 = 0x00B7_04CE;
+const CRC24_POLY: u32 = 0x0186_4CFB;
+
+/// CRC-24 as used by OpenPGP ASCII armor (RFC 4880, section 6.1).
+fn crc24(data: &[u8]) -> u32 {
+    let mut crc = CRC24_INIT;
+    for byte in data {
+        crc ^= (*byte as u32) << 16;
+        for _ in 0..8 {
+            crc <<= 1;
+            if crc & 0x0100_0000 != 0 {
+                crc ^= CRC24_POLY;
+            }
+        }
+    }
+    crc & 0x00FF_FFFF
+}
+
+pub fn is_armored(text: &str) -> bool {
+    text.trim_start().starts_with(BEGIN)
+}
+
+pub fn armor(label: &str, version: u8, body: &[u8]) -> String {
+    // armored bodies usually hold key material, so avoid leaving copies behind
+    let encoded = Zeroizing::new(general_purpose::STANDARD.encode(body));
+    let checksum = general_purpose::STANDARD.encode(&crc24(body).to_be_bytes()[1..]);
+
+    let mut out = String::with_capacity(encoded.len() * 2 + 4 * label.len() + 64);
+    out.push_str(&format!("{BEGIN}{label}{DASHES}\nVersion: {version}\n\n"));
+    for line in encoded.as_bytes().chunks(LINE_WIDTH) {
+        // base64 output is always ASCII
+        out.push_str(std::str::from_utf8(line).unwrap());
+        out.push('\n');
+    }
+    out.push_str(&format!("={checksum}\n{END}{label}{DASHES}\n"));
+    out
+}
+
+/// Parses an armored block with the given label, returning its version and body.
+pub fn dearmor(label: &str, text: &str) -> Result<(u8, Vec<u8>), Box<dyn Error>> {
+    let mut lines = text.lines().map(str::trim).skip_while(|l| l.is_empty());
+
+    if lines.next() != Some(&format!("{BEGIN}{label}{DASHES}")) {
+        Err(format!("Missing '{label}' armor header"))?
+    }
+
+    let mut version = None;
+    for line in lines.by_ref() {
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Version:") {
+            version = Some(value.trim().parse::<u8>()?);
+        }
+    }
+    let version = version.ok_or("Missing armor version")?;
+
+    let mut encoded = Zeroizing::new(String::with_capacity(text.len()));
+    let mut checksum = None;
+    let mut closed = false;
+    for line in lines {
+        if line == format!("{END}{label}{DASHES}") {
+            closed = true;
+            break;
+        } else if let Some(value) = line.strip_prefix('=') {
+            checksum = Some(value.to_owned());
+        } else {
+            encoded.push_str(line);
+        }
+    }
+    if !closed {
+        Err(format!("Missing '{label}' armor footer"))?
+    }
+
+    let body = general_purpose::STANDARD.decode(encoded.as_bytes())?;
+    if let Some(checksum) = checksum {
+        let checksum = general_purpose::STANDARD.decode(checksum)?;
+        if checksum != crc24(&body).to_be_bytes()[1..] {
+            Err("Armor checksum mismatch")?
+        }
+    } else {
+        Err("Missing armor checksum")?
+    }
+    Ok((version, body))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn armor_dearmor_test() {
+        let body: Vec<u8> = (0..=255).collect();
+        let text = armor("FILEGRAM TEST", 1, &body);
+
+        assert!(is_armored(&text));
+        assert_eq!((1, body), dearmor("FILEGRAM TEST", &text).unwrap());
+    }
+
+    #[test]
+    fn checksum_mismatch_test() {
+        let text = armor("FILEGRAM TEST", 1, b"filegram");
+        let text = text.replace("ZmlsZWdyYW0", "ZmlsZWdyYW1");
+
+        assert!(dearmor("FILEGRAM TEST", &text).is_err());
+    }
+}
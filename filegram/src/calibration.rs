@@ -0,0 +1,667 @@
+use std::{cmp::Reverse, error::Error};
+
+use image::{Rgb, RgbImage};
+
+use crate::IMAGE_WIDTH;
+
+/// Light modules around the symbol, so the finders stand out from whatever
+/// surrounds a screenshot.
+const QUIET: u32 = 4;
+/// Side of a finder, a dark ring around a light ring around a dark square.
+const FINDER: u32 = 7;
+/// Modules between the edge of the symbol and the data: a finder and the
+/// light separator next to it.
+const MARGIN: u32 = FINDER + 1;
+/// Row and column of the timing patterns, in line with the inner edge of the
+/// finders.
+const TIMING: u32 = FINDER - 1;
+/// Finder centres tried in threes, in the order of how many scan lines
+/// crossed them.
+const MAX_FINDERS: usize = 12;
+
+const DARK: Rgb<u8> = Rgb([0, 0, 0]);
+const LIGHT: Rgb<u8> = Rgb([255, 255, 255]);
+
+/// Copy of a frame image with every pixel drawn as a square `module` pixels
+/// wide, framed by finder and timing patterns as in QR codes, so [`locate`]
+/// can read it back from a rescaled, cropped or screenshotted copy.
+///
+/// The data of odd widths or heights is followed by a light column or row,
+/// which no frame ends with: widths are multiples of
+/// [`IMAGE_WIDTH`](crate::IMAGE_WIDTH), and the last row holds the padded
+/// end of the payload.
+pub fn mark(image: &RgbImage, module: u32) -> RgbImage {
+    let module = module.max(1);
+    let (width, height) = (
+        image.width().next_multiple_of(2),
+        image.height().next_multiple_of(2),
+    );
+    let (columns, rows) = (width + 2 * MARGIN, height + 2 * MARGIN);
+    let mut marked = RgbImage::from_pixel(
+        (columns + 2 * QUIET) * module,
+        (rows + 2 * QUIET) * module,
+        LIGHT,
+    );
+    let mut set = |x: u32, y: u32, color: Rgb<u8>| {
+        for dy in 0..module {
+            for dx in 0..module {
+                marked.put_pixel((QUIET + x) * module + dx, (QUIET + y) * module + dy, color);
+            }
+        }
+    };
+
+    for (left, top) in [(0, 0), (columns - FINDER, 0), (0, rows - FINDER)] {
+        for y in 0..FINDER {
+            for x in 0..FINDER {
+                let ring = x.min(y).min(FINDER - 1 - x).min(FINDER - 1 - y);
+                set(left + x, top + y, if ring == 1 { LIGHT } else { DARK });
+            }
+        }
+    }
+    let timing = |i: u32| if i.is_multiple_of(2) { DARK } else { LIGHT };
+    for x in 0..width {
+        set(MARGIN + x, TIMING, timing(x));
+    }
+    for y in 0..height {
+        set(TIMING, MARGIN + y, timing(y));
+    }
+    for (x, y, pixel) in image.enumerate_pixels() {
+        set(MARGIN + x, MARGIN + y, *pixel);
+    }
+    marked
+}
+
+/// Module size of `image` when it's exactly what [`mark`] draws around
+/// `frame`, rather than a rescaled or cropped copy.
+pub fn module(image: &RgbImage, frame: &RgbImage) -> Option<u32> {
+    let columns = frame.width().next_multiple_of(2) + 2 * (MARGIN + QUIET);
+    let module = image.width() / columns;
+    (module > 0 && mark(frame, module) == *image).then_some(module)
+}
+
+/// Light or dark pixels of an image, by luma.
+struct Bitmap {
+    width: i64,
+    height: i64,
+    dark: Vec<bool>,
+}
+
+impl Bitmap {
+    fn new(image: &RgbImage) -> Self {
+        Self::top_left(image, image.width(), image.height())
+    }
+
+    /// Bitmap of the `width` by `height` pixels in the top left corner.
+    fn top_left(image: &RgbImage, width: u32, height: u32) -> Self {
+        let dark = image
+            .rows()
+            .take(height as usize)
+            .flat_map(|row| row.take(width as usize))
+            .map(|Rgb([r, g, b])| {
+                let luma = 299 * *r as u32 + 587 * *g as u32 + 114 * *b as u32;
+                luma < 128_000
+            })
+            .collect();
+        Bitmap {
+            width: width as i64,
+            height: height as i64,
+            dark,
+        }
+    }
+
+    fn get(&self, x: i64, y: i64) -> Option<bool> {
+        let inside = (0..self.width).contains(&x) && (0..self.height).contains(&y);
+        inside.then(|| self.dark[(y * self.width + x) as usize])
+    }
+
+    /// Whether the pixel under the point `(x, y)` is dark, light outside.
+    fn at(&self, (x, y): (f64, f64)) -> bool {
+        self.get(x.floor() as i64, y.floor() as i64)
+            .unwrap_or(false)
+    }
+}
+
+/// Run lengths across a finder, in units of its modules.
+const FINDER_RUNS: [f64; 5] = [1.0, 1.0, 3.0, 1.0, 1.0];
+
+/// Whether dark, light, dark, light and dark runs of these lengths look
+/// like a line through the middle of a finder.
+fn finder_runs(runs: &[f64; 5]) -> bool {
+    let unit = runs.iter().sum::<f64>() / FINDER as f64;
+    // runs are whole pixels, so small modules get an extra pixel of slack
+    let slack = unit / 2.0 + 0.5;
+    runs.iter()
+        .zip(FINDER_RUNS)
+        .all(|(run, expected)| (run - expected * unit).abs() <= slack)
+}
+
+/// Runs through the dark pixel at `centre` along a line, where `step(i)`
+/// tells if the pixel `i` steps away is dark, giving the centre of the
+/// middle run as an offset from `centre` and the length of all five.
+fn cross_check(step: impl Fn(i64) -> Option<bool>) -> Option<(f64, f64)> {
+    // lengths of the dark middle, light and dark runs on one side
+    let side = |direction: i64| {
+        let mut lengths = [0i64; 3];
+        let mut i = 0;
+        for (run, length) in lengths.iter_mut().enumerate() {
+            let dark = run != 1;
+            while step(i * direction) == Some(dark) {
+                *length += 1;
+                i += 1;
+            }
+            // the outer ring may end at the edge of a tight crop
+            if *length == 0 || (run < 2 && step(i * direction).is_none()) {
+                return None;
+            }
+        }
+        Some(lengths)
+    };
+    let [middle_before, light_before, dark_before] = side(-1)?;
+    let [middle_after, light_after, dark_after] = side(1)?;
+    let middle = middle_before + middle_after - 1;
+    let runs = [dark_before, light_before, middle, light_after, dark_after].map(|run| run as f64);
+    if !finder_runs(&runs) {
+        return None;
+    }
+    let start = 1 - middle_before;
+    Some((start as f64 + middle as f64 / 2.0, runs.iter().sum()))
+}
+
+/// Centre of a finder in pixel edge coordinates, and its module size.
+#[derive(Debug, Clone, Copy)]
+struct Finder {
+    x: f64,
+    y: f64,
+    module: f64,
+    hits: usize,
+}
+
+impl Finder {
+    fn distance(&self, other: &Finder) -> f64 {
+        (self.x - other.x).hypot(self.y - other.y)
+    }
+}
+
+/// Finders crossed by row `y`, confirmed along their column and row through
+/// the middle.
+fn row_finders(bitmap: &Bitmap, y: i64) -> Vec<Finder> {
+    let mut runs: Vec<(i64, f64)> = Vec::new();
+    let mut start = 0;
+    for x in 1..=bitmap.width {
+        if x == bitmap.width || bitmap.get(x, y) != bitmap.get(start, y) {
+            runs.push((start, (x - start) as f64));
+            start = x;
+        }
+    }
+    let first_dark = usize::from(bitmap.get(0, y) != Some(true));
+    let mut finders = Vec::new();
+    for window in runs[first_dark.min(runs.len())..].windows(5).step_by(2) {
+        let lengths = [0, 1, 2, 3, 4].map(|i| window[i].1);
+        if !finder_runs(&lengths) {
+            continue;
+        }
+        let x = window[2].0 + (window[2].1 / 2.0) as i64;
+        let Some((dy, vertical)) = cross_check(|i| bitmap.get(x, y + i)) else {
+            continue;
+        };
+        let cy = y as f64 + dy;
+        let row = cy.floor() as i64;
+        let Some((dx, horizontal)) = cross_check(|i| bitmap.get(x + i, row)) else {
+            continue;
+        };
+        finders.push(Finder {
+            x: x as f64 + dx,
+            y: cy,
+            module: (horizontal + vertical) / (2 * FINDER) as f64,
+            hits: 1,
+        });
+    }
+    finders
+}
+
+/// Whether every module of `finder` and of the light ring around it, which
+/// marked images have, is as dark or light as it should be at its centre.
+fn framed(bitmap: &Bitmap, finder: &Finder) -> bool {
+    let half = (FINDER / 2) as i64;
+    let ring = -half - 1..=half + 1;
+    ring.clone().all(|j| {
+        ring.clone().all(|i| {
+            let distance = i.abs().max(j.abs());
+            let dark = distance == half || distance < half - 1;
+            let point = (
+                finder.x + i as f64 * finder.module,
+                finder.y + j as f64 * finder.module,
+            );
+            bitmap.at(point) == dark
+        })
+    })
+}
+
+/// Finders crossed by the rows of the image, with nearby hits merged.
+fn find_finders(bitmap: &Bitmap) -> Vec<Finder> {
+    let mut finders: Vec<Finder> = Vec::new();
+    for y in 0..bitmap.height {
+        for found in row_finders(bitmap, y) {
+            match finders
+                .iter_mut()
+                .find(|finder| finder.distance(&found) < 2.0 * finder.module.max(found.module))
+            {
+                Some(finder) => {
+                    let hits = finder.hits as f64;
+                    let average = |a: f64, b: f64| (a * hits + b) / (hits + 1.0);
+                    finder.x = average(finder.x, found.x);
+                    finder.y = average(finder.y, found.y);
+                    finder.module = average(finder.module, found.module);
+                    finder.hits += 1;
+                }
+                None => finders.push(found),
+            }
+        }
+    }
+    finders.sort_by_key(|finder| Reverse(finder.hits));
+    finders.truncate(MAX_FINDERS);
+    finders
+}
+
+/// Centres of the dark modules of a timing pattern between the finders of
+/// a symbol, read along a line `offset` pixels off the one through their
+/// centres. It only finds them all while it stays on the pattern, and finds
+/// none when it doesn't start and end in the finders.
+fn timing(bitmap: &Bitmap, from: &Finder, to: &Finder, offset: (f64, f64)) -> Vec<(f64, f64)> {
+    let module = (from.module + to.module) / 2.0;
+    let length = from.distance(to);
+    let samples = (length * 4.0).ceil() as usize;
+    let point = |t: f64| {
+        (
+            from.x + (to.x - from.x) * t + offset.0,
+            from.y + (to.y - from.y) * t + offset.1,
+        )
+    };
+    // the line starts and ends in the dark rows of the finders in line with
+    // the pattern, 2 modules from the centres, clear of their light rings
+    let end = 2.0 * module / length;
+    let normal = ((from.y - to.y) / length, (to.x - from.x) / length);
+    let at = |i: f64| end + (1.0 - 2.0 * end) * i / samples as f64;
+    let dark: Vec<bool> = (0..=samples)
+        .map(|i| bitmap.at(point(at(i as f64))))
+        .collect();
+    // the edges of modules in a rotated image are jagged, so every sample
+    // takes the majority of a window around it, which evens them out
+    // without moving the middle of a run
+    let per_module = module * samples as f64 / length;
+    let half = (per_module * 0.25) as usize;
+    let smooth = (0..dark.len()).map(|i| {
+        let window = &dark[i.saturating_sub(half)..(i + half + 1).min(dark.len())];
+        2 * window.iter().filter(|&&sample| sample).count() > window.len()
+    });
+    // dark or light runs, with the first sample of each
+    let mut runs: Vec<(bool, usize, usize)> = Vec::new();
+    for (i, dark) in smooth.enumerate() {
+        match runs.last_mut() {
+            Some((last, _, length)) if *last == dark => *length += 1,
+            _ => runs.push((dark, i, 1)),
+        }
+    }
+    // what's left of the jagged edges is much shorter than a module
+    let min_run = per_module * 0.35;
+    let mut merged: Vec<(bool, usize, usize)> = Vec::new();
+    for (dark, start, length) in runs {
+        match merged.last_mut() {
+            Some((last, _, total)) if *last == dark || (length as f64) < min_run => {
+                *total += length
+            }
+            _ => merged.push((dark, start, length)),
+        }
+    }
+    // light and dark runs alternate between the finders, the light ones
+    // including the separators, and the ends have to be well inside the
+    // finders, not grazing their edges
+    match merged.as_slice() {
+        [(true, _, first), inner @ .., (true, _, last)] if (*first.min(last) as f64) >= min_run => {
+            inner
+                .iter()
+                .filter(|(dark, ..)| *dark)
+                .map(|&(_, start, length)| {
+                    let centre = point(at(start as f64 + (length - 1) as f64 / 2.0));
+                    centre_across(bitmap, centre, normal, module)
+                })
+                .collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Middle of the dark run through `point` along `normal`, a unit vector.
+/// Timing modules are light on both sides across the pattern, so this
+/// finds their centres across it, up to a module away.
+fn centre_across(
+    bitmap: &Bitmap,
+    point: (f64, f64),
+    normal: (f64, f64),
+    module: f64,
+) -> (f64, f64) {
+    let along = |s: f64| (point.0 + normal.0 * s, point.1 + normal.1 * s);
+    let edge = |direction: f64| {
+        let mut s = 0.0;
+        while s < module && bitmap.at(along(direction * (s + 0.25))) {
+            s += 0.25;
+        }
+        direction * s
+    };
+    along((edge(-1.0) + edge(1.0)) / 2.0)
+}
+
+/// Corners of the symbol, in the order top left, top right, bottom left.
+struct Corners {
+    top_left: Finder,
+    top_right: Finder,
+    bottom_left: Finder,
+}
+
+impl Corners {
+    /// Corners with a right angle at `top_left`, if the finders could be
+    /// those of one symbol.
+    fn new(top_left: Finder, a: Finder, b: Finder) -> Option<Self> {
+        let module = top_left.module;
+        if [a.module, b.module]
+            .iter()
+            .any(|other| other / module > 1.5 || module / other > 1.5)
+        {
+            return None;
+        }
+        let u = (a.x - top_left.x, a.y - top_left.y);
+        let v = (b.x - top_left.x, b.y - top_left.y);
+        let (du, dv) = (u.0.hypot(u.1), v.0.hypot(v.1));
+        // the centres of the finders are at least the margins and two data
+        // modules apart, less the rounding of the module size
+        let min = (2 * MARGIN - FINDER + 2) as f64 * module * 0.8;
+        if du < min || dv < min || (u.0 * v.0 + u.1 * v.1).abs() / (du * dv) > 0.2 {
+            return None;
+        }
+        // the top right finder is clockwise of the bottom left one, with y
+        // going down
+        Some(match u.0 * v.1 - u.1 * v.0 > 0.0 {
+            true => Corners {
+                top_left,
+                top_right: a,
+                bottom_left: b,
+            },
+            false => Corners {
+                top_left,
+                top_right: b,
+                bottom_left: a,
+            },
+        })
+    }
+
+    fn area(&self) -> f64 {
+        self.top_left.distance(&self.top_right) * self.top_left.distance(&self.bottom_left)
+    }
+
+    /// Timing patterns of the symbol, checked against the module size.
+    fn timing(&self, bitmap: &Bitmap) -> Option<Timing> {
+        // lines a little above and below the estimated middle of the timing
+        // pattern, as the module size is only known roughly; leaving the
+        // pattern can only lose modules
+        let read = |to: &Finder, towards: &Finder| {
+            let from = &self.top_left;
+            let distance = from.distance(towards);
+            (-2..=2)
+                .map(|step| {
+                    let modules = TIMING as f64 + 0.5 - FINDER as f64 / 2.0 + 0.15 * step as f64;
+                    let shift = modules * from.module / distance;
+                    let offset = ((towards.x - from.x) * shift, (towards.y - from.y) * shift);
+                    timing(bitmap, from, to, offset)
+                })
+                .max_by_key(Vec::len)
+                .unwrap_or_default()
+        };
+        let timing = Timing {
+            across: read(&self.top_right, &self.bottom_left),
+            down: read(&self.bottom_left, &self.top_right),
+        };
+        let matches = |modules: u32, distance: f64| {
+            let module = distance / (modules + 2 * MARGIN - FINDER) as f64;
+            (module / self.top_left.module - 1.0).abs() < 0.25
+        };
+        let (width, height) = timing.size();
+        let fits = width > 0
+            && height > 0
+            && matches(width, self.top_left.distance(&self.top_right))
+            && matches(height, self.top_left.distance(&self.bottom_left));
+        fits.then_some(timing)
+    }
+}
+
+/// Centres of the dark timing modules along the top and left of the data.
+struct Timing {
+    across: Vec<(f64, f64)>,
+    down: Vec<(f64, f64)>,
+}
+
+impl Timing {
+    /// Width and height of the data, padded to even numbers.
+    fn size(&self) -> (u32, u32) {
+        (2 * self.across.len() as u32, 2 * self.down.len() as u32)
+    }
+}
+
+/// Least squares line `a + b * t` through the points at `t`, if there are
+/// two or more.
+fn fit(points: &[(f64, (f64, f64))]) -> Option<((f64, f64), (f64, f64))> {
+    if points.len() < 2 {
+        return None;
+    }
+    let n = points.len() as f64;
+    let (mut t, mut x, mut y) = (0.0, 0.0, 0.0);
+    for (s, point) in points {
+        t += s / n;
+        x += point.0 / n;
+        y += point.1 / n;
+    }
+    let (mut tt, mut tx, mut ty) = (0.0, 0.0, 0.0);
+    for (s, point) in points {
+        tt += (s - t) * (s - t);
+        tx += (s - t) * (point.0 - x);
+        ty += (s - t) * (point.1 - y);
+    }
+    let b = (tx / tt, ty / tt);
+    Some(((x - b.0 * t, y - b.1 * t), b))
+}
+
+/// Whether `image` looks marked, with a finder and the light ring around it
+/// in its top left quarter, where copies of marked images keep their top
+/// left finder unless borders take up most of them. Unlike [`locate`], it
+/// doesn't search the whole image.
+pub fn probe(image: &RgbImage) -> bool {
+    let bitmap = Bitmap::top_left(image, image.width().div_ceil(2), image.height().div_ceil(2));
+    (0..bitmap.height).any(|y| {
+        row_finders(&bitmap, y)
+            .iter()
+            .any(|finder| framed(&bitmap, finder))
+    })
+}
+
+/// Frame image inside the markers drawn by [`mark`], read from an image that
+/// may have been scaled, cropped, shifted, slightly rotated or framed by
+/// other content since. Every module is sampled at its centre, so scaling
+/// must keep colours, as nearest neighbour scaling does.
+pub fn locate(image: &RgbImage) -> Result<RgbImage, Box<dyn Error>> {
+    let bitmap = Bitmap::new(image);
+    let finders = find_finders(&bitmap);
+    let mut symbols = Vec::new();
+    for (i, &a) in finders.iter().enumerate() {
+        for (j, &b) in finders.iter().enumerate().skip(i + 1) {
+            for &c in &finders[j + 1..] {
+                symbols.extend(Corners::new(a, b, c));
+                symbols.extend(Corners::new(b, a, c));
+                symbols.extend(Corners::new(c, a, b));
+            }
+        }
+    }
+    // finders in the data make smaller symbols than the real corners
+    symbols.sort_by(|a, b| b.area().total_cmp(&a.area()));
+    let Some((corners, timing)) = symbols.into_iter().find_map(|corners| {
+        let timing = corners.timing(&bitmap)?;
+        Some((corners, timing))
+    }) else {
+        Err("Couldn't find the calibration markers")?
+    };
+    let (width, height) = timing.size();
+    let unpadded = width - width % IMAGE_WIDTH as u32;
+    if unpadded == 0 || width - unpadded > 1 {
+        Err(format!(
+            "Marked data isn't a multiple of {IMAGE_WIDTH} pixels wide"
+        ))?
+    }
+
+    // the centres of the timing modules, whose module coordinates are
+    // known, give the sides of the data more precisely than the finders do
+    let centres = |points: &[(f64, f64)]| -> Vec<(f64, (f64, f64))> {
+        points
+            .iter()
+            .enumerate()
+            .map(|(i, &point)| ((MARGIN + 2 * i as u32) as f64 + 0.5, point))
+            .collect()
+    };
+    let (across, down) = (centres(&timing.across), centres(&timing.down));
+    let timing_middle = TIMING as f64 + 0.5;
+    let (top, u) = fit(&across).ok_or("Couldn't read the calibration markers")?;
+    // the timing pattern of data 2 modules high has one dark module, so the
+    // direction of the left side comes from the finders
+    let (left, v) = fit(&down).unwrap_or_else(|| {
+        let (top_left, bottom_left) = (corners.top_left, corners.bottom_left);
+        let modules = (height + 2 * MARGIN - FINDER) as f64;
+        let v = (
+            (bottom_left.x - top_left.x) / modules,
+            (bottom_left.y - top_left.y) / modules,
+        );
+        let (t, (x, y)) = down[0];
+        ((x - v.0 * t, y - v.1 * t), v)
+    });
+    // the top side runs through row TIMING, the left one through column
+    // TIMING, which both place the origin
+    let origin = (
+        (top.0 - v.0 * timing_middle + left.0 - u.0 * timing_middle) / 2.0,
+        (top.1 - v.1 * timing_middle + left.1 - u.1 * timing_middle) / 2.0,
+    );
+    let pixel = |s: f64, t: f64| (origin.0 + u.0 * s + v.0 * t, origin.1 + u.1 * s + v.1 * t);
+    let mut data = RgbImage::new(unpadded, height);
+    for (x, y, value) in data.enumerate_pixels_mut() {
+        let (px, py) = pixel((MARGIN + x) as f64 + 0.5, (MARGIN + y) as f64 + 0.5);
+        let (px, py) = (px.floor(), py.floor());
+        if px < 0.0 || py < 0.0 || px >= image.width() as f64 || py >= image.height() as f64 {
+            Err("Calibration markers reach outside the image")?
+        }
+        *value = *image.get_pixel(px as u32, py as u32);
+    }
+
+    // the light row after data of an odd height
+    let last = (height - 1) * data.width() * 3;
+    if data.as_raw()[last as usize..]
+        .iter()
+        .all(|&byte| byte == 255)
+    {
+        let raw = data.as_raw()[..last as usize].to_vec();
+        data = RgbImage::from_raw(unpadded, height - 1, raw).ok_or("Invalid image size")?;
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{decode, encode, header::Header};
+
+    /// Nearest neighbour copy of `image` onto a `width` by `height` canvas,
+    /// where `source` maps every canvas pixel centre to a point of `image`.
+    fn warp(
+        image: &RgbImage,
+        (width, height): (u32, u32),
+        source: impl Fn(f64, f64) -> (f64, f64),
+    ) -> RgbImage {
+        let background = Rgb([90, 110, 140]);
+        RgbImage::from_fn(width, height, |x, y| {
+            let (sx, sy) = source(x as f64 + 0.5, y as f64 + 0.5);
+            let (sx, sy) = (sx.floor(), sy.floor());
+            if sx < 0.0 || sy < 0.0 || sx >= image.width() as f64 || sy >= image.height() as f64 {
+                background
+            } else {
+                *image.get_pixel(sx as u32, sy as u32)
+            }
+        })
+    }
+
+    #[test]
+    fn calibration_test() {
+        // noisy payloads, whose pixels sometimes look like finders
+        let mut state = 7u32;
+        let payload: Vec<u8> = (0..40_100)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            })
+            .collect();
+        let header = Header {
+            file_name: Some("noise.bin".to_owned()),
+            ..Header::new()
+        };
+        let odd = encode::with_header(&header, &payload);
+        let wide = encode::Encoder::with_columns(&header, &payload[..9500], 2).into_image();
+        assert_eq!(1, odd.height() % 2);
+        assert_eq!(0, wide.height() % 2);
+
+        for image in [&odd, &wide] {
+            // the markers alone
+            assert!(probe(&mark(image, 1)));
+            assert_eq!(*image, locate(&mark(image, 1)).unwrap());
+            assert_eq!(Some(3), module(&mark(image, 3), image));
+
+            // upscaled twice by a host
+            let marked = mark(image, 1);
+            let (w, h) = marked.dimensions();
+            let scaled = warp(&marked, (2 * w, 2 * h), |x, y| (x / 2.0, y / 2.0));
+            assert!(probe(&scaled));
+            assert_eq!(*image, locate(&scaled).unwrap());
+            // which is the same as marking with larger modules
+            assert_eq!(Some(2), module(&scaled, image));
+
+            // cropped into the quiet zone and screenshotted with borders
+            let marked = mark(image, 2);
+            let (w, h) = marked.dimensions();
+            let shot = warp(&marked, (w + 37, h + 61), |x, y| {
+                let (x, y) = (x - 23.0, y - 40.0);
+                match x < 5.0 || y < 5.0 || x > w as f64 - 5.0 || y > h as f64 - 5.0 {
+                    true => (-1.0, -1.0),
+                    false => (x, y),
+                }
+            });
+            assert!(probe(&shot));
+            assert_eq!(*image, locate(&shot).unwrap());
+            assert_eq!(None, module(&shot, image));
+
+            // scaled by 1.5 and slightly rotated
+            let marked = mark(image, 4);
+            let (w, h) = marked.dimensions();
+            let (sin, cos) = 1.5f64.to_radians().sin_cos();
+            let (cx, cy) = (w as f64 * 0.75, h as f64 * 0.75);
+            let rotated = warp(&marked, (w * 3 / 2 + 40, h * 3 / 2 + 40), |x, y| {
+                let (x, y) = (x - 20.0 - cx, y - 20.0 - cy);
+                let (x, y) = (x * cos + y * sin, y * cos - x * sin);
+                ((x + cx) / 1.5, (y + cy) / 1.5)
+            });
+            assert!(probe(&rotated));
+            let located = locate(&rotated).unwrap();
+            assert_eq!(
+                decode::split_header(image).unwrap(),
+                decode::split_header(&located).unwrap()
+            );
+        }
+
+        // the payload itself has no markers
+        assert!(!probe(&odd));
+        assert!(!probe(&wide));
+        assert!(locate(&odd).is_err());
+        assert!(locate(&RgbImage::new(300, 200)).is_err());
+    }
+}
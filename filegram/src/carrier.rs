@@ -0,0 +1,240 @@
+use std::{error::Error, fmt, str::FromStr};
+
+use crc32fast::Hasher;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+const IEND: [u8; 4] = *b"IEND";
+
+/// Type of the chunks holding a payload: ancillary, private and safe to
+/// copy, so viewers skip them and editors that understand PNG keep them.
+pub const CHUNK_TYPE: [u8; 4] = *b"fgRm";
+/// Payload bytes per chunk, well below the limits decoders put on ancillary
+/// chunks.
+const CHUNK_DATA_SIZE: usize = 1024 * 1024;
+
+/// Where a PNG file holds a payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Carrier {
+    /// the pixels, written by the image encoder
+    Pixels,
+    /// private chunks before the end of a cover image
+    Chunk,
+    /// the same chunks appended after the end of a cover image
+    Trailer,
+}
+
+impl Carrier {
+    pub const ALL: [Carrier; 3] = [Carrier::Pixels, Carrier::Chunk, Carrier::Trailer];
+}
+
+impl fmt::Display for Carrier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Carrier::Pixels => "pixels",
+            Carrier::Chunk => "chunk",
+            Carrier::Trailer => "trailer",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Carrier {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Carrier::ALL
+            .into_iter()
+            .find(|carrier| carrier.to_string() == s.trim().to_lowercase())
+            .ok_or_else(|| format!("Invalid carrier '{s}', expected pixels, chunk or trailer"))
+    }
+}
+
+struct Chunk<'a> {
+    kind: [u8; 4],
+    data: &'a [u8],
+    start: usize,
+    end: usize,
+}
+
+/// Chunks of `data` from `start` to the first IEND chunk, or to the end of
+/// `data` when `until_end` is set.
+fn read_chunks(
+    data: &[u8],
+    mut start: usize,
+    until_end: bool,
+) -> Result<Vec<Chunk<'_>>, Box<dyn Error>> {
+    let mut chunks = Vec::new();
+    while start < data.len() {
+        let Some(prefix) = data.get(start..start + 8) else {
+            Err("Truncated PNG chunk")?
+        };
+        let len = u32::from_be_bytes(prefix[..4].try_into().expect("4 bytes")) as usize;
+        let kind: [u8; 4] = prefix[4..].try_into().expect("4 bytes");
+        // the length is untrusted and can overflow a 32-bit usize
+        let end = len
+            .checked_add(start + 8 + 4)
+            .ok_or("Truncated PNG chunk")?;
+        let Some(chunk) = data.get(start + 4..end) else {
+            Err("Truncated PNG chunk")?
+        };
+        let (typed, crc) = chunk.split_at(4 + len);
+        if crc32(typed) != crc {
+            Err(format!(
+                "Damaged PNG chunk {}",
+                String::from_utf8_lossy(&kind)
+            ))?
+        }
+        chunks.push(Chunk {
+            kind,
+            data: &typed[4..],
+            start,
+            end,
+        });
+        start = end;
+        if kind == IEND && !until_end {
+            return Ok(chunks);
+        }
+    }
+    match until_end {
+        true => Ok(chunks),
+        false => Err("PNG file without an IEND chunk")?,
+    }
+}
+
+fn crc32(typed: &[u8]) -> [u8; 4] {
+    let mut hasher = Hasher::new();
+    hasher.update(typed);
+    hasher.finalize().to_be_bytes()
+}
+
+/// Chunks of a PNG file up to IEND, the IEND chunk included.
+fn image_chunks(png: &[u8]) -> Result<Vec<Chunk<'_>>, Box<dyn Error>> {
+    if !png.starts_with(&SIGNATURE) {
+        Err("Not a PNG file")?
+    }
+    read_chunks(png, SIGNATURE.len(), false)
+}
+
+fn write_chunks(output: &mut Vec<u8>, data: &[u8]) {
+    // an empty payload still gets a chunk, so it can be found
+    for part in data
+        .chunks(CHUNK_DATA_SIZE)
+        .chain(data.is_empty().then_some(&[][..]))
+    {
+        output.extend_from_slice(&(part.len() as u32).to_be_bytes());
+        let start = output.len();
+        output.extend_from_slice(&CHUNK_TYPE);
+        output.extend_from_slice(part);
+        let crc = crc32(&output[start..]);
+        output.extend_from_slice(&crc);
+    }
+}
+
+/// Copy of the `cover` PNG file carrying `data` in private chunks, before
+/// its IEND chunk or after it. Payloads it carried before are replaced.
+pub(crate) fn embed(
+    cover: &[u8],
+    carrier: Carrier,
+    data: &[u8],
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    if carrier == Carrier::Pixels {
+        Err("Pixels can't carry a payload next to a cover image")?
+    }
+    let chunks = image_chunks(cover)?;
+    let mut output = Vec::with_capacity(cover.len() + data.len() + 1024);
+    output.extend_from_slice(&SIGNATURE);
+    for chunk in &chunks {
+        if chunk.kind == IEND && carrier == Carrier::Chunk {
+            write_chunks(&mut output, data);
+        }
+        if chunk.kind != CHUNK_TYPE {
+            output.extend_from_slice(&cover[chunk.start..chunk.end]);
+        }
+    }
+    if carrier == Carrier::Trailer {
+        write_chunks(&mut output, data);
+    }
+    Ok(output)
+}
+
+/// Data carried by `png` in private chunks or after its end, `None` when it
+/// doesn't carry any there.
+pub(crate) fn extract(png: &[u8], carrier: Carrier) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let chunks = image_chunks(png)?;
+    let chunks = match carrier {
+        Carrier::Pixels => Err("Pixel payloads are read by decoding the image")?,
+        Carrier::Chunk => chunks,
+        Carrier::Trailer => {
+            let end = chunks.last().expect("IEND chunk").end;
+            read_chunks(png, end, true)?
+        }
+    };
+    let mut carried = chunks
+        .iter()
+        .filter(|chunk| chunk.kind == CHUNK_TYPE)
+        .peekable();
+    if carried.peek().is_none() {
+        return Ok(None);
+    }
+    Ok(Some(
+        carried.flat_map(|chunk| chunk.data).copied().collect(),
+    ))
+}
+
+/// Hidden carrier holding data in `png`, preferring chunks over a trailer.
+/// Trailing bytes that aren't our chunks, which some tools append, are
+/// ignored.
+pub(crate) fn find(png: &[u8]) -> Result<Option<Carrier>, Box<dyn Error>> {
+    let chunks = image_chunks(png)?;
+    if chunks.iter().any(|chunk| chunk.kind == CHUNK_TYPE) {
+        return Ok(Some(Carrier::Chunk));
+    }
+    let end = chunks.last().expect("IEND chunk").end;
+    let trailer = read_chunks(png, end, true).unwrap_or_default();
+    let carried = trailer.iter().any(|chunk| chunk.kind == CHUNK_TYPE);
+    Ok(carried.then_some(Carrier::Trailer))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use image::{ImageFormat, RgbImage};
+
+    use super::*;
+
+    #[test]
+    fn carrier_test() {
+        let mut cover = Vec::new();
+        RgbImage::from_pixel(16, 16, [200, 100, 50].into())
+            .write_to(&mut Cursor::new(&mut cover), ImageFormat::Png)
+            .unwrap();
+        assert_eq!(None, find(&cover).unwrap());
+        let data = b"filegram ".repeat(200_000);
+
+        let png = embed(&cover, Carrier::Chunk, &data).unwrap();
+        assert_eq!(Some(Carrier::Chunk), find(&png).unwrap());
+        assert_eq!(Some(data.clone()), extract(&png, Carrier::Chunk).unwrap());
+        assert_eq!(None, extract(&png, Carrier::Trailer).unwrap());
+        let replaced = embed(&png, Carrier::Chunk, b"").unwrap();
+        assert_eq!(
+            Some(Vec::new()),
+            extract(&replaced, Carrier::Chunk).unwrap()
+        );
+
+        let png = embed(&cover, Carrier::Trailer, &data).unwrap();
+        assert_eq!(Some(Carrier::Trailer), find(&png).unwrap());
+        assert_eq!(Some(data), extract(&png, Carrier::Trailer).unwrap());
+        assert!(extract(&png[..png.len() - 1], Carrier::Trailer).is_err());
+        assert_eq!(None, find(&[&cover[..], b"junk"].concat()).unwrap());
+        assert_eq!(Ok(Carrier::Trailer), "Trailer".parse());
+        assert!("exif".parse::<Carrier>().is_err());
+
+        let image = image::load_from_memory(&png).unwrap();
+        assert_eq!((16, 16), (image.width(), image.height()));
+        assert!(embed(b"GIF89a", Carrier::Chunk, &[]).is_err());
+
+        let bogus = [&cover[..8], &[0xff; 4], b"IDAT"].concat();
+        assert!(read_chunks(&bogus, 8, true).is_err());
+    }
+}
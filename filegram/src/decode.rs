@@ -1,30 +1,464 @@
+#[cfg(feature = "async")]
+use std::io;
 use std::{
+    borrow::Cow,
     error::Error,
-    io::{BufRead, Seek},
+    io::{BufRead, Cursor, Seek},
 };
 
-use block_padding::UnpadError;
-use image::{ImageFormat, RgbImage};
+use filegram_core::frame;
+use image::{DynamicImage, ImageFormat, RgbImage};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
-use crate::padding::unpad_block;
+#[cfg(feature = "async")]
+use crate::utils::{Shared, SharedReader};
+
+use crate::{
+    calibration,
+    carrier::{self, Carrier},
+    header::Header,
+    label,
+    progress::{Cancel, Cancelled, Progress, Status},
+    BATCH_ROWS, BUFFER_SIZE, IMAGE_WIDTH,
+};
 
 pub fn from_file<R: BufRead + Seek>(input: R) -> Result<Vec<u8>, Box<dyn Error>> {
-    let img = image::load(input, ImageFormat::Png)?;
-    if let Some(img) = img.as_rgb8() {
-        Ok(from_rgb(img)?)
-    } else {
-        Err("Couldn't read image as RGB")?
+    let image = image_from_file(input)?;
+    from_rgb(&*frame_image(&image)?)
+}
+
+/// RGB image of a PNG file. Opaque RGBA images, as screenshots often are,
+/// are read without their alpha channel.
+pub fn image_from_file<R: BufRead + Seek>(input: R) -> Result<RgbImage, Box<dyn Error>> {
+    match image::load(input, ImageFormat::Png)? {
+        DynamicImage::ImageRgb8(img) => Ok(img),
+        DynamicImage::ImageRgba8(img) if img.pixels().all(|pixel| pixel[3] == u8::MAX) => {
+            Ok(DynamicImage::ImageRgba8(img).to_rgb8())
+        }
+        _ => Err("Couldn't read image as RGB")?,
+    }
+}
+
+/// Frame image in `input_image`: the image itself when it's one, or the one
+/// read through its calibration markers, after a host rescaled it or it was
+/// screenshotted. Frame wide images without a header or markers are taken as
+/// they are, as images written before headers were, without searching them
+/// for markers.
+pub fn frame_image(input_image: &RgbImage) -> Result<Cow<'_, RgbImage>, Box<dyn Error>> {
+    let width = input_image.width() as usize;
+    let frame = width > 0 && width.is_multiple_of(IMAGE_WIDTH);
+    if frame
+        && (matches!(read_header(input_image), Ok(Some(_))) || !calibration::probe(input_image))
+    {
+        return Ok(Cow::Borrowed(input_image));
+    }
+    match calibration::locate(input_image) {
+        Ok(located) => Ok(Cow::Owned(located)),
+        Err(_) if frame => Ok(Cow::Borrowed(input_image)),
+        Err(error) => Err(error),
+    }
+}
+
+/// Raw buffer of an image, whose rows are `BUFFER_SIZE` bytes each, without
+/// the rows filling out the last pixel row of a wide image, and the number
+/// of rows in each pixel row.
+fn raw_rows(input_image: &RgbImage) -> Result<(&[u8], usize), Box<dyn Error>> {
+    let width = input_image.width() as usize;
+    if width == 0 || !width.is_multiple_of(IMAGE_WIDTH) {
+        Err(format!(
+            "Image width isn't a multiple of {IMAGE_WIDTH} pixels"
+        ))?
     }
+    let columns = width / IMAGE_WIDTH;
+    Ok((frame::trim_fill(input_image.as_raw(), columns), columns))
 }
 
-pub fn from_rgb(input_image: &RgbImage) -> Result<Vec<u8>, UnpadError> {
-    let mut rows: Vec<Vec<u8>> = input_image
-        .enumerate_rows()
-        .map(|(_, row)| row.flat_map(|(_, _, rgb)| rgb.0).collect())
-        .collect();
-    if let Some(last) = rows.last_mut() {
-        *last = unpad_block(last)?;
+/// Copies whole rows, each on its own thread with the parallel feature.
+fn copy_rows(to: &mut [u8], from: &[u8]) {
+    #[cfg(feature = "parallel")]
+    to.par_chunks_mut(BUFFER_SIZE)
+        .zip(from.par_chunks(BUFFER_SIZE))
+        .for_each(|(to, from)| to.copy_from_slice(from));
+    #[cfg(not(feature = "parallel"))]
+    to.copy_from_slice(from);
+}
+
+/// Copies the data out of the payload rows to `data` a batch at a time,
+/// leaving out the padding of the last row. `status` counts the rows before
+/// them.
+fn copy_payload(
+    rows: &[u8],
+    data: &mut [u8],
+    mut status: Status,
+    progress: &mut dyn Progress,
+    cancel: &Cancel,
+) -> Result<(), Box<dyn Error>> {
+    if data.len() != frame::payload(rows)?.len() {
+        Err("Output doesn't match the payload size")?
+    }
+    let batch_len = BATCH_ROWS * BUFFER_SIZE;
+    for (i, batch) in rows.chunks(batch_len).enumerate() {
+        cancel.check()?;
+        let start = i * batch_len;
+        let end = (start + batch.len()).min(data.len());
+        // the data of the last row is followed by its padding
+        copy_rows(&mut data[start..end], &batch[..end - start]);
+        status.rows_done += batch.len() / BUFFER_SIZE;
+        status.bytes_done += batch.len();
+        progress.update(status);
     }
-    let data = rows.into_iter().flatten().collect();
+    Ok(())
+}
+
+/// [`copy_payload`] into a new buffer.
+fn payload(
+    rows: &[u8],
+    status: Status,
+    progress: &mut dyn Progress,
+    cancel: &Cancel,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut data = vec![0u8; frame::payload(rows)?.len()];
+    copy_payload(rows, &mut data, status, progress, cancel)?;
     Ok(data)
 }
+
+pub fn from_rgb(input_image: &RgbImage) -> Result<Vec<u8>, Box<dyn Error>> {
+    from_rgb_cancellable(input_image, &mut |_| {}, &Cancel::new())
+}
+
+/// [`from_rgb`] reporting its progress to `progress` and stopping when
+/// `cancel` is cancelled.
+pub fn from_rgb_cancellable(
+    input_image: &RgbImage,
+    progress: &mut dyn Progress,
+    cancel: &Cancel,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let (raw, _) = raw_rows(input_image)?;
+    payload(raw, header_status(raw, 0), progress, cancel)
+}
+
+/// Reads only the header rows of an image.
+pub fn read_header(input_image: &RgbImage) -> Result<Option<Header>, Box<dyn Error>> {
+    let raw = input_image.as_raw();
+    let first_row = &raw[..BUFFER_SIZE.min(raw.len())];
+    if !Header::is_present(first_row) {
+        return Ok(None);
+    }
+    let len = Header::encoded_len(first_row)?;
+    if len > raw.len() {
+        Err("Truncated image")?
+    }
+    Ok(Some(Header::from_bytes(&raw[..len])?.0))
+}
+
+/// Header of an image `columns` rows wide and the length of its header and
+/// banner rows, after which the payload rows start. Images without a header
+/// have no header rows.
+fn split_rows(raw: &[u8], columns: usize) -> Result<(Option<Header>, usize), Box<dyn Error>> {
+    let header_len = frame::header_len(raw)?;
+    if header_len == 0 {
+        return Ok((None, 0));
+    }
+    let (header, _) = Header::from_bytes(&raw[..header_len])?;
+    let banner = label::banner_span(header_len / BUFFER_SIZE, header.banner_rows, columns)
+        .ok_or("Truncated image")?;
+    let start = banner
+        .end
+        .checked_mul(BUFFER_SIZE)
+        .filter(|start| *start < raw.len())
+        .ok_or("Truncated image")?;
+    Ok((Some(header), start))
+}
+
+/// Splits an image into its header and payload. Images written without a
+/// header decode to `None` and the whole image as payload.
+pub fn split_header(input_image: &RgbImage) -> Result<(Option<Header>, Vec<u8>), Box<dyn Error>> {
+    split_header_cancellable(input_image, &mut |_| {}, &Cancel::new())
+}
+
+/// [`split_header`] reporting its progress to `progress` and stopping when
+/// `cancel` is cancelled.
+pub fn split_header_cancellable(
+    input_image: &RgbImage,
+    progress: &mut dyn Progress,
+    cancel: &Cancel,
+) -> Result<(Option<Header>, Vec<u8>), Box<dyn Error>> {
+    let (raw, columns) = raw_rows(input_image)?;
+    let (header, header_len) = split_rows(raw, columns)?;
+    let status = header_status(raw, header_len);
+    Ok((
+        header,
+        payload(&raw[header_len..], status, progress, cancel)?,
+    ))
+}
+
+/// Status of a decode of the rows in `raw` that has read the `header_len`
+/// bytes of header rows.
+fn header_status(raw: &[u8], header_len: usize) -> Status {
+    Status {
+        rows_done: header_len / BUFFER_SIZE,
+        rows_total: raw.len() / BUFFER_SIZE,
+        bytes_done: header_len,
+        bytes_total: raw.len(),
+    }
+}
+
+/// Decoder reading the payload of an image a few rows at a time, for
+/// callers that can't block until it's all read.
+pub struct Decoder {
+    image: RgbImage,
+    header: Option<Header>,
+    /// Bytes of the header and banner rows, after which the payload starts.
+    start: usize,
+    /// Bytes of the rows of the frame.
+    len: usize,
+    data: Vec<u8>,
+    /// Payload rows read so far.
+    row: usize,
+}
+
+impl Decoder {
+    /// Decoder of the frame image in `image`, see [`frame_image`], whose
+    /// header is read up front.
+    pub fn new(image: RgbImage) -> Result<Self, Box<dyn Error>> {
+        let located = match frame_image(&image)? {
+            Cow::Owned(located) => Some(located),
+            Cow::Borrowed(_) => None,
+        };
+        let image = located.unwrap_or(image);
+        let (raw, columns) = raw_rows(&image)?;
+        let (header, start) = split_rows(raw, columns)?;
+        let data = vec![0u8; frame::payload(&raw[start..])?.len()];
+        Ok(Decoder {
+            header,
+            start,
+            len: raw.len(),
+            data,
+            row: 0,
+            image,
+        })
+    }
+
+    pub fn header(&self) -> Option<&Header> {
+        self.header.as_ref()
+    }
+
+    pub fn status(&self) -> Status {
+        let mut status = header_status(&self.image.as_raw()[..self.len], self.start);
+        status.rows_done += self.row;
+        status.bytes_done += self.row * BUFFER_SIZE;
+        status
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.start + self.row * BUFFER_SIZE == self.len
+    }
+
+    /// Reads up to `rows` more rows and tells whether the payload is done.
+    pub fn step(&mut self, rows: usize) -> bool {
+        let end = self
+            .row
+            .saturating_add(rows)
+            .min((self.len - self.start) / BUFFER_SIZE);
+        let rows = &self.image.as_raw()[self.start..self.len];
+        // the data of the last row is followed by its padding
+        let (from, to) = (
+            (self.row * BUFFER_SIZE).min(self.data.len()),
+            (end * BUFFER_SIZE).min(self.data.len()),
+        );
+        copy_rows(&mut self.data[from..to], &rows[from..to]);
+        self.row = end;
+        self.is_done()
+    }
+
+    /// Reads the remaining rows in batches, reporting each to `progress`
+    /// and stopping when `cancel` is cancelled.
+    pub fn run(
+        mut self,
+        progress: &mut dyn Progress,
+        cancel: &Cancel,
+    ) -> Result<(Option<Header>, Vec<u8>), Cancelled> {
+        loop {
+            cancel.check()?;
+            let done = self.step(BATCH_ROWS);
+            progress.update(self.status());
+            if done {
+                return Ok((self.header, self.data));
+            }
+        }
+    }
+
+    /// Header and payload, reading the remaining rows first.
+    pub fn into_parts(mut self) -> (Option<Header>, Vec<u8>) {
+        self.step(usize::MAX);
+        (self.header, self.data)
+    }
+}
+
+/// Size of the payload after the header, for sizing the output of
+/// [`payload_into`].
+pub fn payload_len(input_image: &RgbImage) -> Result<usize, Box<dyn Error>> {
+    let (raw, columns) = raw_rows(input_image)?;
+    let (_, header_len) = split_rows(raw, columns)?;
+    Ok(frame::payload(&raw[header_len..])?.len())
+}
+
+/// Copies the payload after the header to `output`, e.g. a memory-mapped
+/// file, which must be [`payload_len`] bytes long.
+pub fn payload_into(input_image: &RgbImage, output: &mut [u8]) -> Result<(), Box<dyn Error>> {
+    payload_into_cancellable(input_image, output, &mut |_| {}, &Cancel::new())
+}
+
+/// [`payload_into`] reporting its progress to `progress` and stopping when
+/// `cancel` is cancelled.
+pub fn payload_into_cancellable(
+    input_image: &RgbImage,
+    output: &mut [u8],
+    progress: &mut dyn Progress,
+    cancel: &Cancel,
+) -> Result<(), Box<dyn Error>> {
+    let (raw, columns) = raw_rows(input_image)?;
+    let (_, header_len) = split_rows(raw, columns)?;
+    let status = header_status(raw, header_len);
+    copy_payload(&raw[header_len..], output, status, progress, cancel)
+}
+
+/// Splits the payload carried by a PNG file into its header and payload,
+/// like [`split_header`] does for pixels.
+pub fn from_carrier(
+    png: &[u8],
+    carrier: Carrier,
+) -> Result<(Option<Header>, Vec<u8>), Box<dyn Error>> {
+    if carrier == Carrier::Pixels {
+        let image = image_from_file(Cursor::new(png))?;
+        return split_header(&*frame_image(&image)?);
+    }
+    let Some(mut data) = carrier::extract(png, carrier)? else {
+        Err(format!("Nothing carried in the {carrier}"))?
+    };
+    let (header, len) = Header::from_bytes(&data)?;
+    data.drain(..len);
+    Ok((Some(header), data))
+}
+
+/// Carrier of the payload of a PNG file: the private chunks or trailer of a
+/// cover image if it has them, otherwise the pixels.
+pub fn find_carrier(png: &[u8]) -> Result<Carrier, Box<dyn Error>> {
+    Ok(carrier::find(png)?.unwrap_or(Carrier::Pixels))
+}
+
+/// Carriers of a PNG file that still hold an intact payload with a header,
+/// e.g. after the file went through an image host.
+pub fn survey(png: &[u8]) -> Vec<Carrier> {
+    Carrier::ALL
+        .into_iter()
+        .filter(|carrier| matches!(from_carrier(png, *carrier), Ok((Some(_), _))))
+        .collect()
+}
+
+/// Next row of a PNG image, reading more of `input` whenever the decoder
+/// runs out of bytes.
+#[cfg(feature = "async")]
+async fn next_row(
+    reader: &mut png::Reader<SharedReader>,
+    received: &Shared,
+    input: &mut (impl AsyncRead + Unpin),
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    loop {
+        match reader.next_row() {
+            Ok(Some(row)) => return Ok(row.data().to_vec()),
+            Ok(None) => Err("Truncated image")?,
+            Err(png::DecodingError::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                if !received.fill_from(input).await? {
+                    Err(e)?
+                }
+            }
+            Err(e) => Err(e)?,
+        }
+    }
+}
+
+/// Streams the payload of a PNG image read from `input` to `output`, row by
+/// row, and returns the image header. Images written without a header give
+/// `None`.
+#[cfg(feature = "async")]
+pub async fn decode_async(
+    mut input: impl AsyncRead + Unpin,
+    mut output: impl AsyncWrite + Unpin,
+) -> Result<Option<Header>, Box<dyn Error>> {
+    let received = Shared::default();
+    let mut start = Vec::new();
+    // the decoder can't resume reading the PNG header, so it starts over
+    // whenever more of it arrives
+    let mut reader = loop {
+        match png::Decoder::new(received.reader(start.clone())).read_info() {
+            Ok(reader) => break reader,
+            Err(png::DecodingError::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                if !received.fill_from(&mut input).await? {
+                    Err(e)?
+                }
+                start.extend(received.take());
+            }
+            Err(e) => Err(e)?,
+        }
+    };
+    let info = reader.info();
+    let width = info.width as usize;
+    if width == 0
+        || !width.is_multiple_of(IMAGE_WIDTH)
+        || info.color_type != png::ColorType::Rgb
+        || info.bit_depth != png::BitDepth::Eight
+    {
+        Err(format!(
+            "Image isn't RGB and a multiple of {IMAGE_WIDTH} pixels wide"
+        ))?
+    }
+    let columns = width / IMAGE_WIDTH;
+    let rows = info.height as usize * columns;
+
+    let mut header = None;
+    let mut header_rows = 0;
+    let mut header_bytes = Vec::new();
+    // rows before the payload, the banner rows included
+    let mut start = 0;
+    // the payload rows of the last pixel row are written once the rows
+    // filling it out and the padding are removed
+    let mut last = Vec::new();
+    let mut index = 0;
+    for _ in 0..info.height {
+        let pixels = next_row(&mut reader, &received, &mut input).await?;
+        output.write_all(&last).await?;
+        last.clear();
+        for row in pixels.chunks(BUFFER_SIZE) {
+            if index == 0 && Header::is_present(row) {
+                header_rows = Header::encoded_len(row)?.div_ceil(BUFFER_SIZE);
+                if header_rows >= rows {
+                    Err("Truncated image")?
+                }
+            }
+            if index < header_rows {
+                header_bytes.extend_from_slice(row);
+                if index + 1 == header_rows {
+                    let (read, _) = Header::from_bytes(&header_bytes)?;
+                    start = label::banner_span(header_rows, read.banner_rows, columns)
+                        .ok_or("Truncated image")?
+                        .end;
+                    if start >= rows {
+                        Err("Truncated image")?
+                    }
+                    header = Some(read);
+                }
+            } else if index >= start {
+                last.extend_from_slice(row);
+            }
+            index += 1;
+        }
+    }
+    let last = frame::trim_fill(&last, columns);
+    output.write_all(frame::payload(last)?).await?;
+    output.flush().await?;
+    Ok(header)
+}
@@ -0,0 +1,140 @@
+use std::error::Error;
+
+use argon2::Argon2;
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use zeroize::Zeroizing;
+
+use crate::encryption::{Cipher, Key, KEY_SIZE, NONCE_SIZE, TAG_SIZE};
+
+const SALT_SIZE: usize = 16;
+
+/// Payload encrypted under one passphrase, usually an
+/// [`envelope`](crate::envelope) so that its padding can be told apart from
+/// the data.
+pub struct Layer<'a> {
+    pub passphrase: &'a str,
+    pub data: &'a [u8],
+}
+
+/// Builds a payload of two equally sized halves, each holding a salt and a
+/// ciphertext. The decoy goes into a random half and the hidden layer into
+/// the other one; without a hidden layer that half is random bytes, which
+/// can't be told apart from a ciphertext under an unknown passphrase.
+///
+/// Both halves are as large as the decoy, whether there is a hidden layer or
+/// not, so the hidden layer can't be larger than the decoy. Pad the decoy
+/// envelope to make room for a larger one.
+pub fn seal(decoy: &Layer, hidden: Option<&Layer>, aad: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    if hidden.is_some_and(|hidden| hidden.passphrase == decoy.passphrase) {
+        Err("The hidden passphrase must differ from the decoy passphrase")?
+    }
+    let capacity = decoy.data.len();
+    if hidden.is_some_and(|hidden| hidden.data.len() > capacity) {
+        Err("The hidden file must not be larger than the decoy")?
+    }
+    let decoy = seal_layer(decoy, capacity, aad)?;
+    let hidden = match hidden {
+        Some(hidden) => seal_layer(hidden, capacity, aad)?,
+        None => {
+            let mut random = vec![0u8; decoy.len()];
+            OsRng.fill_bytes(&mut random);
+            random
+        }
+    };
+    let (first, second) = if OsRng.next_u32() & 1 == 0 {
+        (decoy, hidden)
+    } else {
+        (hidden, decoy)
+    };
+    Ok([first, second].concat())
+}
+
+/// Decrypts whichever half of `payload` opens with `passphrase`.
+pub fn open(payload: &[u8], passphrase: &str, aad: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    if !payload.len().is_multiple_of(2) || payload.len() < 2 * (SALT_SIZE + TAG_SIZE) {
+        Err("Invalid deniable payload")?
+    }
+    let (first, second) = payload.split_at(payload.len() / 2);
+    // both halves are always tried, so the time taken doesn't tell which
+    // one opened
+    let first = open_layer(first, passphrase, aad);
+    let second = open_layer(second, passphrase, aad);
+    first
+        .or(second)
+        .map_err(|_| "Wrong passphrase or modified image".into())
+}
+
+fn seal_layer(layer: &Layer, capacity: usize, aad: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut salt = [0u8; SALT_SIZE];
+    OsRng.fill_bytes(&mut salt);
+    // random rather than zero slack, in case a passphrase leaks the layer
+    let mut data = Zeroizing::new(vec![0u8; capacity]);
+    OsRng.fill_bytes(&mut data[layer.data.len()..]);
+    data[..layer.data.len()].copy_from_slice(layer.data);
+
+    let cipher = Cipher::load(&layer_key(layer.passphrase, &salt)?);
+    let mut sealed = salt.to_vec();
+    sealed.extend_from_slice(&cipher.encrypt(&data, aad));
+    Ok(sealed)
+}
+
+fn open_layer(half: &[u8], passphrase: &str, aad: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let (salt, ciphertext) = half.split_at(SALT_SIZE);
+    Cipher::load(&layer_key(passphrase, salt)?).decrypt(ciphertext, aad)
+}
+
+/// Each layer's key and nonce come from its passphrase and a fresh salt, so
+/// the image stores nothing but the salt.
+fn layer_key(passphrase: &str, salt: &[u8]) -> Result<Key, Box<dyn Error>> {
+    let mut bytes = Zeroizing::new([0u8; KEY_SIZE + NONCE_SIZE]);
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, bytes.as_mut())
+        .map_err(|e| format!("Couldn't derive layer key: {e}"))?;
+    Key::from_bytes(bytes.as_ref())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{encode, header::Header};
+
+    #[test]
+    fn seal_open_test() {
+        let decoy = Layer {
+            passphrase: "decoy",
+            data: b"shopping list!",
+        };
+        let hidden = Layer {
+            passphrase: "hidden",
+            data: b"the real thing",
+        };
+        let payload = seal(&decoy, Some(&hidden), b"aad").unwrap();
+        let without_hidden = seal(&decoy, None, b"aad").unwrap();
+
+        assert_eq!(payload.len(), without_hidden.len());
+        let opened = open(&payload, "decoy", b"aad").unwrap();
+        assert_eq!(b"shopping list!".to_vec(), opened);
+        let opened = open(&payload, "hidden", b"aad").unwrap();
+        assert_eq!(b"the real thing".to_vec(), opened);
+        assert!(open(&without_hidden, "hidden", b"aad").is_err());
+        assert!(open(&payload, "decoy", b"other").is_err());
+
+        // the image is as large with a hidden layer as without
+        let header = Header {
+            encrypted: true,
+            private: true,
+            deniable: true,
+            ..Header::new()
+        };
+        assert_eq!(
+            encode::with_header(&header, &payload).dimensions(),
+            encode::with_header(&header, &without_hidden).dimensions()
+        );
+
+        let larger = Layer {
+            passphrase: "hidden",
+            data: b"the real thing, and then some",
+        };
+        assert!(seal(&decoy, Some(&larger), b"aad").is_err());
+    }
+}
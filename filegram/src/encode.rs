@@ -1,63 +1,381 @@
-use std::io::Read;
-
-use image::{Rgb, RgbImage};
-use imageproc::{drawing::draw_filled_rect_mut, rect::Rect};
-
-use crate::{padding::pad_block, utils::read_exact, BUFFER_SIZE, IMAGE_WIDTH};
-
-fn update_frame(image: &mut RgbImage, data: Vec<u8>, shift: usize) {
-    data.chunks(3)
-        .map(|chunk| {
-            let mut triple = [0u8; 3];
-            triple[..chunk.len()].copy_from_slice(chunk);
-            triple
-        })
-        .map(Rgb)
-        .enumerate()
-        .for_each(|(i, color)| {
-            let i = (i + shift) as i32;
-            let (x, y) = (i % IMAGE_WIDTH as i32, i / IMAGE_WIDTH as i32);
-            draw_filled_rect_mut(image, Rect::at(x, y).of_size(1, 1), color);
-        });
-}
-
-pub fn from_reader(mut input: &mut impl Read, file_size: usize) -> RgbImage {
+use std::{
+    borrow::Cow,
+    error::Error,
+    io::{Read, Write},
+    ops::Range,
+};
+
+use filegram_core::{frame, padding::pad_block};
+use image::{codecs::png::PngEncoder, ImageError, RgbImage};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+#[cfg(feature = "async")]
+use crate::utils::{read_exact_async, Shared};
+
+use crate::{
+    carrier::{self, Carrier},
+    header::Header,
+    label::{self, Label},
+    progress::{Cancel, Cancelled, Progress, Status},
+    utils::read_exact,
+    BATCH_ROWS, BUFFER_SIZE, IMAGE_WIDTH,
+};
+
+pub use image::codecs::png::{CompressionType, FilterType};
+
+/// Copies `data` to consecutive rows of a raw image buffer. Rows that `data`
+/// doesn't fill are padded.
+fn write_rows(rows: &mut [u8], data: &[u8]) {
+    // each row is BUFFER_SIZE bytes of the raw buffer, filled independently
+    #[cfg(feature = "parallel")]
+    let rows = rows.par_chunks_mut(BUFFER_SIZE);
+    #[cfg(not(feature = "parallel"))]
+    let rows = rows.chunks_mut(BUFFER_SIZE);
+    rows.enumerate()
+        .for_each(|(row, pixels)| frame::write_row(pixels, frame::row_data(data, row)));
+}
+
+pub fn from_reader(input: &mut impl Read, file_size: usize) -> Result<RgbImage, Box<dyn Error>> {
+    from_reader_cancellable(input, file_size, &mut |_| {}, &Cancel::new())
+}
+
+/// [`from_reader`] reporting its progress to `progress` and stopping with
+/// [`Cancelled`] when `cancel` is cancelled.
+pub fn from_reader_cancellable(
+    mut input: &mut impl Read,
+    file_size: usize,
+    progress: &mut dyn Progress,
+    cancel: &Cancel,
+) -> Result<RgbImage, Box<dyn Error>> {
     let height = (file_size / BUFFER_SIZE) + 1;
     let mut image = RgbImage::new(IMAGE_WIDTH as u32, height as u32);
+    let mut status = Status {
+        rows_total: height,
+        bytes_total: file_size,
+        ..Status::default()
+    };
 
-    let mut bytes = [0u8; BUFFER_SIZE];
-    let mut shift = 0;
-
-    while let Ok(n) = read_exact(&mut input, &mut bytes) {
-        if n == 0 {
+    // the input is read straight into the image, a batch of rows at a time
+    let raw: &mut [u8] = &mut image;
+    let mut start = 0;
+    while start < raw.len() {
+        cancel.check()?;
+        let end = (start + BATCH_ROWS * BUFFER_SIZE).min(raw.len());
+        let n = read_exact(&mut input, &mut raw[start..end])?;
+        status.bytes_done += n;
+        if n < end - start {
+            // the last row is always padded, even if it holds no data
+            let last = start + n - n % BUFFER_SIZE;
+            pad_block(&mut raw[last..last + BUFFER_SIZE], n % BUFFER_SIZE);
+            status.rows_done = last / BUFFER_SIZE + 1;
+            progress.update(status);
             break;
-        };
-        let block = if n < BUFFER_SIZE {
-            pad_block(bytes[..n].to_vec())
-        } else {
-            bytes.to_vec()
-        };
-        update_frame(&mut image, block, shift);
-        shift += IMAGE_WIDTH;
+        }
+        start = end;
+        status.rows_done = start / BUFFER_SIZE;
+        progress.update(status);
+    }
+    Ok(image)
+}
+
+/// Encoder writing an image a few rows at a time, for callers that can't
+/// block until the whole image is done.
+pub struct Encoder<'a> {
+    image: RgbImage,
+    payload: Cow<'a, [u8]>,
+    /// Rows before the payload, taken by the header and the banner, which
+    /// are written up front.
+    start: usize,
+    /// Rows of the banner, blank until a label is drawn in them.
+    banner: Range<usize>,
+    /// Rows of the frame, not counting those filling out the last pixel row.
+    rows: usize,
+    row: usize,
+}
+
+impl<'a> Encoder<'a> {
+    pub fn new(payload: impl Into<Cow<'a, [u8]>>) -> Self {
+        Self::from_parts(&[], 0, payload.into(), 1)
+    }
+
+    /// Encodes `payload` preceded by `header` rows.
+    pub fn with_header(header: &Header, payload: impl Into<Cow<'a, [u8]>>) -> Self {
+        Self::with_columns(header, payload, 1)
+    }
+
+    /// [`with_header`](Self::with_header) in an image `columns` rows wide,
+    /// e.g. the [`Plan::columns`](crate::layout::Plan::columns) of a layout.
+    /// Zeroed rows fill out its last pixel row, and the banner rows of the
+    /// header follow the header rows, see [`label`](Self::label).
+    pub fn with_columns(
+        header: &Header,
+        payload: impl Into<Cow<'a, [u8]>>,
+        columns: usize,
+    ) -> Self {
+        Self::from_parts(
+            &header.to_bytes(),
+            header.banner_rows,
+            payload.into(),
+            columns,
+        )
+    }
+
+    fn from_parts(header: &[u8], banner_rows: u32, payload: Cow<'a, [u8]>, columns: usize) -> Self {
+        let columns = columns.max(1);
+        // like the image buffer, whose size overflows first
+        let banner = label::banner_span(header.len().div_ceil(BUFFER_SIZE), banner_rows, columns)
+            .expect("banner rows overflow");
+        let rows = banner.end + frame::height(0, payload.len());
+        let mut image = RgbImage::new(
+            (IMAGE_WIDTH * columns) as u32,
+            rows.div_ceil(columns) as u32,
+        );
+        // the rest of the header rows stays zeroed
+        let raw: &mut [u8] = &mut image;
+        raw[..header.len()].copy_from_slice(header);
+        Encoder {
+            image,
+            payload,
+            start: banner.end,
+            row: banner.end,
+            banner,
+            rows,
+        }
+    }
+
+    /// Draws `label` in the banner rows the header reserved with
+    /// [`Header::banner_rows`], usually [`Label::ROWS`]. Images without
+    /// them are left as they are.
+    pub fn label(mut self, label: &Label) -> Self {
+        let banner = label.render(self.image.width());
+        let raw: &mut [u8] = &mut self.image;
+        let rows = &mut raw[self.banner.start * BUFFER_SIZE..self.banner.end * BUFFER_SIZE];
+        let len = rows.len().min(banner.as_raw().len());
+        rows[..len].copy_from_slice(&banner.as_raw()[..len]);
+        self
+    }
+
+    pub fn status(&self) -> Status {
+        let written = (self.row - self.start) * BUFFER_SIZE;
+        Status {
+            rows_done: self.row,
+            rows_total: self.rows,
+            bytes_done: written.min(self.payload.len()),
+            bytes_total: self.payload.len(),
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.row == self.rows
+    }
+
+    /// Writes up to `rows` more rows and tells whether the image is done.
+    pub fn step(&mut self, rows: usize) -> bool {
+        let end = self.row.saturating_add(rows).min(self.rows);
+        let start = ((self.row - self.start) * BUFFER_SIZE).min(self.payload.len());
+        let raw: &mut [u8] = &mut self.image;
+        write_rows(
+            &mut raw[self.row * BUFFER_SIZE..end * BUFFER_SIZE],
+            &self.payload[start..],
+        );
+        self.row = end;
+        self.is_done()
+    }
+
+    /// Writes the remaining rows in batches, reporting each to `progress`
+    /// and stopping when `cancel` is cancelled.
+    pub fn run(
+        mut self,
+        progress: &mut dyn Progress,
+        cancel: &Cancel,
+    ) -> Result<RgbImage, Cancelled> {
+        loop {
+            cancel.check()?;
+            let done = self.step(BATCH_ROWS);
+            progress.update(self.status());
+            if done {
+                return Ok(self.image);
+            }
+        }
+    }
+
+    /// Finished image, writing the remaining rows first.
+    pub fn into_image(mut self) -> RgbImage {
+        self.step(usize::MAX);
+        self.image
     }
-    image
 }
 
 pub fn from_slice(input: &[u8]) -> RgbImage {
-    let height = (input.len() / BUFFER_SIZE) + 1;
-    let mut image = RgbImage::new(IMAGE_WIDTH as u32, height as u32);
+    Encoder::new(input).into_image()
+}
+
+/// Encodes `payload` preceded by `header` rows.
+pub fn with_header(header: &Header, payload: &[u8]) -> RgbImage {
+    Encoder::with_header(header, payload).into_image()
+}
+
+/// [`with_header`] reporting its progress to `progress` and stopping when
+/// `cancel` is cancelled.
+pub fn with_header_cancellable(
+    header: &Header,
+    payload: &[u8],
+    progress: &mut dyn Progress,
+    cancel: &Cancel,
+) -> Result<RgbImage, Cancelled> {
+    Encoder::with_header(header, payload).run(progress, cancel)
+}
+
+/// Copy of the `cover` PNG file carrying `payload` preceded by `header` in
+/// a [`Carrier`] other than its pixels, which still show the cover image.
+pub fn carry(
+    cover: &[u8],
+    carrier: Carrier,
+    header: &Header,
+    payload: &[u8],
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut data = header.to_bytes();
+    data.extend_from_slice(payload);
+    carrier::embed(cover, carrier, &data)
+}
+
+/// PNG settings for [`write_png`], which decoders don't need to know. The
+/// defaults are those of `RgbImage::save`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PngOptions {
+    pub compression: CompressionType,
+    pub filter: FilterType,
+}
+
+/// Writes `image` to `output` as PNG.
+pub fn write_png(
+    image: &RgbImage,
+    output: impl Write,
+    options: &PngOptions,
+) -> Result<(), ImageError> {
+    let encoder = PngEncoder::new_with_quality(output, options.compression, options.filter);
+    image.write_with_encoder(encoder)
+}
+
+/// Streams an image with `header` rows and `payload_len` bytes read from
+/// `input` to `output` as PNG, row by row.
+#[cfg(feature = "async")]
+pub async fn encode_async(
+    header: &Header,
+    mut input: impl AsyncRead + Unpin,
+    payload_len: usize,
+    mut output: impl AsyncWrite + Unpin,
+) -> Result<(), Box<dyn Error>> {
+    let header = header.to_bytes();
+    let header_rows = header.len().div_ceil(BUFFER_SIZE);
+    let payload_rows = (payload_len / BUFFER_SIZE) + 1;
+
+    let sink = Shared::default();
+    let height = header_rows + payload_rows;
+    let mut encoder = png::Encoder::new(sink.clone(), IMAGE_WIDTH as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut stream = encoder.write_header()?.into_stream_writer()?;
 
-    let mut shift = 0;
-
-    input.chunks(BUFFER_SIZE).for_each(|bytes| {
-        let n = bytes.len();
-        let block = if n < BUFFER_SIZE {
-            pad_block(bytes[..n].to_vec())
-        } else {
-            bytes.to_vec()
-        };
-        update_frame(&mut image, block, shift);
-        shift += IMAGE_WIDTH;
-    });
-    image
+    let mut row = [0u8; BUFFER_SIZE];
+    for bytes in header.chunks(BUFFER_SIZE) {
+        row.fill(0);
+        row[..bytes.len()].copy_from_slice(bytes);
+        stream.write_all(&row)?;
+    }
+    for start in (0..payload_rows).map(|row| row * BUFFER_SIZE) {
+        let n = read_exact_async(&mut input, &mut row).await?;
+        if n != BUFFER_SIZE.min(payload_len - start) {
+            Err("Input length doesn't match the payload length")?
+        }
+        // the last row is always padded, even if it holds no data
+        if n < BUFFER_SIZE {
+            pad_block(&mut row, n);
+        }
+        stream.write_all(&row)?;
+        sink.drain_to(&mut output).await?;
+    }
+    stream.finish()?;
+    sink.drain_to(&mut output).await?;
+    output.flush().await?;
+    Ok(())
+}
+
+/// Replaces the header rows of an image encoded with [`with_header`],
+/// copying the banner and payload rows as they are. The new header must
+/// keep the banner rows of the old one.
+pub fn replace_header(image: &RgbImage, header: &Header) -> Result<RgbImage, Box<dyn Error>> {
+    let columns = (image.width() as usize / IMAGE_WIDTH).max(1);
+    let raw = frame::trim_fill(image.as_raw(), columns);
+    let old_len = Header::encoded_len(&raw[..BUFFER_SIZE.min(raw.len())])?;
+    if old_len > raw.len() {
+        Err("Truncated image")?
+    }
+    let (old, _) = Header::from_bytes(&raw[..old_len])?;
+    if old.banner_rows != header.banner_rows {
+        Err("The new header doesn't keep the banner rows")?
+    }
+    let old_banner = label::banner_span(old_len.div_ceil(BUFFER_SIZE), old.banner_rows, columns)
+        .ok_or("Truncated image")?;
+    old_banner
+        .end
+        .checked_mul(BUFFER_SIZE)
+        .filter(|end| *end < raw.len())
+        .ok_or("Truncated image")?;
+
+    let mut buffer = header.to_bytes();
+    let banner = label::banner_span(
+        buffer.len().div_ceil(BUFFER_SIZE),
+        header.banner_rows,
+        columns,
+    )
+    .ok_or("Too many banner rows")?;
+    buffer.resize(banner.start * BUFFER_SIZE, 0);
+    buffer.extend_from_slice(&raw[old_banner.start * BUFFER_SIZE..]);
+
+    // the new header can change how many rows fill out the last pixel row
+    let height = (buffer.len() / BUFFER_SIZE).div_ceil(columns);
+    buffer.resize(height * columns * BUFFER_SIZE, 0);
+    Ok(
+        RgbImage::from_raw((IMAGE_WIDTH * columns) as u32, height as u32, buffer)
+            .ok_or("Invalid image size")?,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// The serial and parallel row writers must both give this layout.
+    #[test]
+    fn layout_test() {
+        let header = Header::new();
+        let payload: Vec<u8> = (0..BATCH_ROWS * BUFFER_SIZE + 100)
+            .map(|i| i as u8)
+            .collect();
+
+        let mut expected = header.to_bytes();
+        let header_len = expected.len().div_ceil(BUFFER_SIZE) * BUFFER_SIZE;
+        expected.resize(header_len, 0);
+        expected.extend_from_slice(&payload[..BATCH_ROWS * BUFFER_SIZE]);
+        let mut last = payload[BATCH_ROWS * BUFFER_SIZE..].to_vec();
+        last.resize(BUFFER_SIZE, 0);
+        pad_block(&mut last, 100);
+        expected.extend(last);
+        assert_eq!(&expected, with_header(&header, &payload).as_raw());
+
+        let from_reader = from_reader(&mut &payload[..], payload.len()).unwrap();
+        assert_eq!(&expected[header_len..], from_reader.as_raw().as_slice());
+
+        struct Failing;
+        impl Read for Failing {
+            fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::BrokenPipe.into())
+            }
+        }
+        assert!(super::from_reader(&mut Failing, payload.len()).is_err());
+    }
 }
@@ -1,23 +1,257 @@
-use std::error::Error;
+use std::{error::Error, fmt, str::FromStr};
 
-use block_padding::generic_array::GenericArray;
-use chacha20poly1305::{
-    aead::{Aead, OsRng},
-    consts::{U12, U32},
-    AeadCore, ChaCha20Poly1305, KeyInit,
+use base64::{
+    alphabet,
+    engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig},
+    Engine,
 };
-use serde::{Deserialize, Serialize};
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use hkdf::Hkdf;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use subtle::{Choice, ConstantTimeEq};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
 
-#[derive(Serialize, Deserialize)]
+use crate::armor;
+
+mod mnemonic;
+#[cfg(feature = "pkcs11")]
+pub mod pkcs11;
+mod shamir;
+
+pub use shamir::{combine_shares, split_key, KeyShare};
+
+const KEY_LABEL: &str = "FILEGRAM KEY";
+const KEY_VERSION: u8 = 1;
+const DERIVE_INFO: &[u8] = b"filegram file key v1";
+const CONVERGENT_INFO: &[u8] = b"filegram convergent key v1";
+const KEY_ID_DOMAIN: &[u8] = b"filegram key id v1";
+pub const KEY_ID_SIZE: usize = 8;
+pub use filegram_core::cipher::{KEY_SIZE, NONCE_SIZE, TAG_SIZE};
+
+/// Base64 engine accepting both padded and unpadded legacy web app keys.
+const LEGACY_BASE64: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// 256-bit secret key, wiped from memory on drop and never printed.
+#[derive(Zeroize, ZeroizeOnDrop)]
+pub struct SecretKey([u8; KEY_SIZE]);
+
+impl SecretKey {
+    pub fn generate() -> Self {
+        let mut key = SecretKey([0u8; KEY_SIZE]);
+        OsRng.fill_bytes(&mut key.0);
+        key
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        if bytes.len() != KEY_SIZE {
+            Err("Invalid key length")?
+        }
+        let mut key = SecretKey([0u8; KEY_SIZE]);
+        key.0.copy_from_slice(bytes);
+        Ok(key)
+    }
+
+    pub fn expose(&self) -> &[u8; KEY_SIZE] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretKey([REDACTED])")
+    }
+}
+
+impl ConstantTimeEq for SecretKey {
+    fn ct_eq(&self, other: &Self) -> Choice {
+        self.0.ct_eq(&other.0)
+    }
+}
+
+impl PartialEq for SecretKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.ct_eq(other).into()
+    }
+}
+
+impl Eq for SecretKey {}
+
+/// Public fingerprint of a [`Key`], recorded in images so the key can be
+/// found again without revealing it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct KeyId([u8; KEY_ID_SIZE]);
+
+impl KeyId {
+    pub fn from_bytes(bytes: [u8; KEY_ID_SIZE]) -> Self {
+        KeyId(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; KEY_ID_SIZE] {
+        &self.0
+    }
+}
+
+impl fmt::Display for KeyId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{b:02x}"))
+    }
+}
+
+impl FromStr for KeyId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "Invalid key ID '{s}', expected {} hex digits",
+                2 * KEY_ID_SIZE
+            )
+        };
+        if s.len() != 2 * KEY_ID_SIZE || !s.is_ascii() {
+            return Err(invalid());
+        }
+        let mut id = [0u8; KEY_ID_SIZE];
+        for (i, byte) in id.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(KeyId(id))
+    }
+}
+
+/// Key and nonce pair used to encrypt a single image. It can only leave
+/// memory through [`Key::to_armored`].
+#[derive(Debug, PartialEq, Eq)]
 pub struct Key {
+    key: SecretKey,
+    nonce: [u8; NONCE_SIZE],
+}
+
+/// JSON key layout written by older `fig` versions and the web app.
+#[derive(Deserialize, Zeroize, ZeroizeOnDrop)]
+struct LegacyKey {
     key: Vec<u8>,
     nonce: Vec<u8>,
 }
 
+impl Key {
+    pub fn generate() -> Self {
+        let mut nonce = [0u8; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        Key {
+            key: SecretKey::generate(),
+            nonce,
+        }
+    }
+
+    fn from_parts(key: &[u8], nonce: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let key = SecretKey::from_slice(key)?;
+        let nonce = nonce.try_into().map_err(|_| "Invalid nonce length")?;
+        Ok(Key { key, nonce })
+    }
+
+    /// Key followed by nonce, for wrapping the key inside filegram formats.
+    pub(crate) fn to_bytes(&self) -> Zeroizing<Vec<u8>> {
+        let mut bytes = Zeroizing::new(Vec::with_capacity(KEY_SIZE + NONCE_SIZE));
+        bytes.extend_from_slice(self.key.expose());
+        bytes.extend_from_slice(&self.nonce);
+        bytes
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        if bytes.len() != KEY_SIZE + NONCE_SIZE {
+            Err("Invalid key length")?
+        }
+        let (key, nonce) = bytes.split_at(KEY_SIZE);
+        Self::from_parts(key, nonce)
+    }
+
+    pub(crate) fn secret(&self) -> &SecretKey {
+        &self.key
+    }
+
+    /// Identifier of this key, a truncated SHA-256 hash of it.
+    pub fn id(&self) -> KeyId {
+        let hash = Sha256::new()
+            .chain_update(KEY_ID_DOMAIN)
+            .chain_update(self.to_bytes())
+            .finalize();
+        let mut id = [0u8; KEY_ID_SIZE];
+        id.copy_from_slice(&hash[..KEY_ID_SIZE]);
+        KeyId(id)
+    }
+
+    /// Derives a per-file key from this master key and a random per-file
+    /// `salt` with HKDF-SHA256.
+    pub fn derive(&self, salt: &[u8]) -> Key {
+        let hkdf = Hkdf::<Sha256>::new(Some(salt), self.key.expose());
+        let mut bytes = Zeroizing::new([0u8; KEY_SIZE + NONCE_SIZE]);
+        // the output is far below the HKDF-SHA256 limit of 8160 bytes
+        hkdf.expand(DERIVE_INFO, bytes.as_mut()).unwrap();
+        Self::from_bytes(bytes.as_ref()).unwrap()
+    }
+
+    /// Derives the key for `buf` from a keyed hash of it and `aad` under the
+    /// team secret `team`, so that equal inputs get equal keys and nonces.
+    ///
+    /// Anyone holding `team` and a guess of the content can confirm that an
+    /// image holds it, or recover a low-entropy field of it by trying every
+    /// value, which is the price of deduplication.
+    pub fn convergent(team: &Key, buf: &[u8], aad: &[u8]) -> Key {
+        let mut hkdf = hkdf::HkdfExtract::<Sha256>::new(Some(team.key.expose()));
+        // the aad length keeps the boundary between aad and buf unambiguous
+        hkdf.input_ikm(&(aad.len() as u64).to_be_bytes());
+        hkdf.input_ikm(aad);
+        hkdf.input_ikm(buf);
+        let (_, hkdf) = hkdf.finalize();
+        let mut bytes = Zeroizing::new([0u8; KEY_SIZE + NONCE_SIZE]);
+        hkdf.expand(CONVERGENT_INFO, bytes.as_mut()).unwrap();
+        Self::from_bytes(bytes.as_ref()).unwrap()
+    }
+
+    /// Encodes the key in the armored format shared by `fig` and the web app.
+    pub fn to_armored(&self) -> Zeroizing<String> {
+        Zeroizing::new(armor::armor(KEY_LABEL, KEY_VERSION, &self.to_bytes()))
+    }
+
+    /// Parses an armored key, a mnemonic, or one of the legacy formats: raw
+    /// JSON written by older `fig` versions and base64 encoded JSON written by
+    /// the web app.
+    pub fn parse(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let text = std::str::from_utf8(data)?.trim();
+        if armor::is_armored(text) {
+            let (version, body) = armor::dearmor(KEY_LABEL, text)?;
+            let body = Zeroizing::new(body);
+            if version != KEY_VERSION {
+                Err(format!("Unsupported key version {version}"))?
+            }
+            Self::from_bytes(&body)
+        } else if Self::is_mnemonic(text) {
+            Self::from_mnemonic(text)
+        } else if text.starts_with('{') {
+            let legacy: LegacyKey = serde_json::from_str(text)?;
+            Self::from_parts(&legacy.key, &legacy.nonce)
+        } else {
+            let json = Zeroizing::new(LEGACY_BASE64.decode(text)?);
+            let legacy: LegacyKey = serde_json::from_slice(&json)?;
+            Self::from_parts(&legacy.key, &legacy.nonce)
+        }
+    }
+
+    pub(crate) fn copy(&self) -> Self {
+        Key {
+            key: SecretKey(*self.key.expose()),
+            nonce: self.nonce,
+        }
+    }
+}
+
 pub struct Cipher {
-    cipher: ChaCha20Poly1305,
-    key: GenericArray<u8, U32>,
-    nonce: GenericArray<u8, U12>,
+    cipher: filegram_core::cipher::Cipher,
+    key: Key,
 }
 
 impl Default for Cipher {
@@ -28,47 +262,54 @@ impl Default for Cipher {
 
 impl Cipher {
     pub fn new() -> Self {
-        let key = ChaCha20Poly1305::generate_key(&mut OsRng);
-        let cipher = ChaCha20Poly1305::new(&key);
-        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
-        Cipher { cipher, key, nonce }
+        Self::from_key(Key::generate())
     }
 
-    pub fn with_key(key: Vec<u8>) -> Result<Self, Box<dyn Error>> {
-        let key = GenericArray::clone_from_slice(&key);
-        let cipher = ChaCha20Poly1305::new_from_slice(&key)?;
-        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
-        Ok(Cipher { cipher, key, nonce })
+    pub fn with_key(key: SecretKey) -> Self {
+        let mut nonce = [0u8; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        Self::from_key(Key { key, nonce })
     }
 
-    pub fn load(key_struct: &Key) -> Result<Self, Box<dyn Error>> {
-        let key = GenericArray::clone_from_slice(&key_struct.key);
-        let cipher = ChaCha20Poly1305::new_from_slice(&key_struct.key)?;
-        let nonce = GenericArray::clone_from_slice(&key_struct.nonce);
-        Ok(Cipher { cipher, key, nonce })
+    /// Cipher for convergent encryption of `buf`, see [`Key::convergent`].
+    pub fn convergent(team: &Key, buf: &[u8], aad: &[u8]) -> Self {
+        Self::from_key(Key::convergent(team, buf, aad))
     }
 
-    pub fn get_key_struct(&self) -> Key {
-        Key {
-            key: self.key.to_vec(),
-            nonce: self.nonce.to_vec(),
-        }
+    pub fn load(key: &Key) -> Self {
+        Self::from_key(key.copy())
+    }
+
+    fn from_key(key: Key) -> Self {
+        let cipher = filegram_core::cipher::Cipher::new(key.key.expose(), &key.nonce);
+        Cipher { cipher, key }
     }
 
-    pub fn encrypt(&self, buf: &[u8]) -> Vec<u8> {
-        self.cipher.encrypt(&self.nonce, buf).unwrap()
+    pub fn key(&self) -> &Key {
+        &self.key
     }
 
-    pub fn decrypt(&self, buf: &[u8]) -> Vec<u8> {
-        self.cipher.decrypt(&self.nonce, buf).unwrap()
+    pub fn into_key(self) -> Key {
+        self.key
+    }
+
+    /// Encrypts `buf`, authenticating it together with the associated data
+    /// `aad`, e.g. [`Header::associated_data`](crate::header::Header::associated_data).
+    pub fn encrypt(&self, buf: &[u8], aad: &[u8]) -> Vec<u8> {
+        self.cipher.encrypt(buf, aad)
+    }
+
+    /// Decrypts `buf`, failing if it or the associated data `aad` were
+    /// modified, or the key is wrong.
+    pub fn decrypt(&self, buf: &[u8], aad: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(self.cipher.decrypt(buf, aad)?)
     }
 }
 
 #[cfg(test)]
 mod test {
-    use chacha20poly1305::aead::rand_core::RngCore;
-
     use super::*;
+    use crate::header::Header;
 
     #[test]
     fn encrypt_decrypt_test() {
@@ -76,6 +317,94 @@ mod test {
         let mut msg = vec![0u8; 16];
         OsRng.fill_bytes(&mut msg);
 
-        assert_eq!(msg, cipher.decrypt(&cipher.encrypt(&msg)));
+        assert_eq!(
+            msg,
+            cipher.decrypt(&cipher.encrypt(&msg, &[]), &[]).unwrap()
+        );
+    }
+
+    #[test]
+    fn associated_data_test() {
+        let cipher = Cipher::new();
+        let header = Header {
+            encrypted: true,
+            file_name: Some("test.txt".to_owned()),
+            part: 0,
+            parts: 2,
+            ..Header::new()
+        };
+        let aad = header.associated_data();
+        let encrypted = cipher.encrypt(b"filegram", &aad);
+
+        for bit in 0..aad.len() * 8 {
+            let mut tampered = aad.clone();
+            tampered[bit / 8] ^= 1 << (bit % 8);
+            assert!(cipher.decrypt(&encrypted, &tampered).is_err());
+        }
+        assert!(cipher.decrypt(&encrypted, &[]).is_err());
+    }
+
+    #[test]
+    fn derive_test() {
+        let master = Key::generate();
+
+        assert_eq!(master.derive(b"salt"), master.derive(b"salt"));
+        assert_ne!(master.derive(b"salt"), master.derive(b"pepper"));
+        assert_ne!(master.derive(b"salt"), Key::generate().derive(b"salt"));
+    }
+
+    #[test]
+    fn convergent_test() {
+        let team = Key::generate();
+        let key = Key::convergent(&team, b"filegram", b"header");
+
+        assert_eq!(key, Key::convergent(&team, b"filegram", b"header"));
+        assert_ne!(key, Key::convergent(&team, b"filegram!", b"header"));
+        assert_ne!(key, Key::convergent(&team, b"filegram", b"header!"));
+        assert_ne!(key, Key::convergent(&team, b"rfilegram", b"heade"));
+        assert_ne!(
+            key,
+            Key::convergent(&Key::generate(), b"filegram", b"header")
+        );
+    }
+
+    #[test]
+    fn key_id_test() {
+        let key = Key::generate();
+        let id = key.id();
+
+        assert_eq!(id, key.copy().id());
+        assert_ne!(id, Key::generate().id());
+        assert_eq!(id, id.to_string().parse().unwrap());
+        assert!("not an id".parse::<KeyId>().is_err());
+    }
+
+    #[test]
+    fn key_formats_test() {
+        let key = Key::generate();
+        let json = format!(
+            "{{\"key\":{:?},\"nonce\":{:?}}}",
+            key.key.expose(),
+            key.nonce
+        );
+        let formats = [
+            key.to_armored().to_string(),
+            json.clone(),
+            base64::engine::general_purpose::STANDARD_NO_PAD.encode(&json),
+            base64::engine::general_purpose::STANDARD.encode(&json),
+        ];
+
+        for format in formats {
+            assert_eq!(key, Key::parse(format.as_bytes()).unwrap());
+        }
+    }
+
+    #[test]
+    fn redacted_debug_test() {
+        let key = Key::generate();
+        let debug = format!("{:?}", key);
+
+        assert!(debug.contains("REDACTED"));
+        assert!(!debug.contains(&format!("{:?}", key.key.expose())));
     }
 }
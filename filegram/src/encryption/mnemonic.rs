@@ -0,0 +1,101 @@
+use std::{error::Error, sync::OnceLock};
+
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+use super::{Key, KEY_SIZE, NONCE_SIZE};
+
+/// BIP39 English word list.
+const WORD_LIST: &str = include_str!("english.txt");
+const BITS_PER_WORD: usize = 11;
+const ENTROPY_BITS: usize = (KEY_SIZE + NONCE_SIZE) * 8;
+/// One checksum bit per 32 bits of entropy, as in BIP39.
+const CHECKSUM_BITS: usize = ENTROPY_BITS / 32;
+const WORDS: usize = (ENTROPY_BITS + CHECKSUM_BITS) / BITS_PER_WORD;
+
+fn word_list() -> &'static [&'static str] {
+    static LIST: OnceLock<Vec<&'static str>> = OnceLock::new();
+    LIST.get_or_init(|| WORD_LIST.lines().collect())
+}
+
+fn bit(bytes: &[u8], i: usize) -> u16 {
+    ((bytes[i / 8] >> (7 - i % 8)) & 1) as u16
+}
+
+/// Entropy followed by the leading bits of its SHA-256 hash.
+fn with_checksum(entropy: &[u8]) -> Zeroizing<Vec<u8>> {
+    let mut bytes = Zeroizing::new(entropy.to_vec());
+    bytes.extend_from_slice(&Sha256::digest(entropy));
+    bytes
+}
+
+impl Key {
+    /// Encodes the key as 33 words from the BIP39 English list, the last
+    /// one a checksum, so it can be written down or read out.
+    pub fn to_mnemonic(&self) -> Zeroizing<String> {
+        let bytes = with_checksum(&self.to_bytes());
+        let words: Vec<&str> = (0..WORDS)
+            .map(|w| {
+                let index = (0..BITS_PER_WORD)
+                    .fold(0, |acc, b| (acc << 1) | bit(&bytes, w * BITS_PER_WORD + b));
+                word_list()[index as usize]
+            })
+            .collect();
+        Zeroizing::new(words.join(" "))
+    }
+
+    pub fn from_mnemonic(mnemonic: &str) -> Result<Self, Box<dyn Error>> {
+        let words: Vec<String> = mnemonic.split_whitespace().map(str::to_lowercase).collect();
+        if words.len() != WORDS {
+            Err(format!("Mnemonic has to be {WORDS} words long"))?
+        }
+
+        let mut bits = Zeroizing::new(Vec::with_capacity(WORDS * BITS_PER_WORD));
+        for word in &words {
+            let index = word_list()
+                .binary_search(&word.as_str())
+                .map_err(|_| format!("Unknown mnemonic word '{word}'"))?;
+            bits.extend((0..BITS_PER_WORD).rev().map(|b| (index >> b) & 1 == 1));
+        }
+        let mut entropy = Zeroizing::new(vec![0u8; ENTROPY_BITS / 8]);
+        for (i, set) in bits[..ENTROPY_BITS].iter().enumerate() {
+            entropy[i / 8] |= (*set as u8) << (7 - i % 8);
+        }
+
+        let expected = with_checksum(&entropy);
+        let checksum_ok = (ENTROPY_BITS..ENTROPY_BITS + CHECKSUM_BITS)
+            .all(|i| bits[i] as u16 == bit(&expected, i));
+        if !checksum_ok {
+            Err("Mnemonic checksum mismatch")?
+        }
+        Key::from_bytes(&entropy)
+    }
+
+    /// Whether `text` looks like a mnemonic rather than another key format.
+    pub(crate) fn is_mnemonic(text: &str) -> bool {
+        text.split_whitespace().count() == WORDS
+            && text
+                .chars()
+                .all(|c| c.is_ascii_alphabetic() || c.is_whitespace())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mnemonic_test() {
+        let key = Key::generate();
+        let mnemonic = key.to_mnemonic();
+
+        assert_eq!(WORDS, mnemonic.split(' ').count());
+        assert_eq!(key, Key::from_mnemonic(&mnemonic.to_uppercase()).unwrap());
+
+        // the last word holds only checksum bits
+        let mut words: Vec<&str> = mnemonic.split(' ').collect();
+        let last = words.pop().unwrap();
+        words.push(if last == "zoo" { "abandon" } else { "zoo" });
+        assert!(Key::from_mnemonic(&words.join(" ")).is_err());
+    }
+}
@@ -0,0 +1,210 @@
+//! Key slots wrapped by an AES key that never leaves a PKCS#11 token.
+
+use std::{collections::HashMap, error::Error, fs, os::raw::c_ulong};
+
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use cryptoki::{
+    context::{CInitializeArgs, CInitializeFlags, Pkcs11},
+    error::{Error as Pkcs11Error, RvError},
+    mechanism::{aead::GcmParams, Mechanism},
+    object::{Attribute, KeyType, ObjectClass, ObjectHandle},
+    session::{Session, UserType},
+    types::AuthPin,
+};
+use zeroize::Zeroizing;
+
+use super::{Key, NONCE_SIZE};
+use crate::{
+    header::Header,
+    keyslot::{KeySlot, SlotKind},
+};
+
+const GCM_TAG_BITS: c_ulong = 128;
+
+/// Parsed `pkcs11:` URI as described in RFC 7512, limited to the attributes
+/// needed to find a secret key.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Uri {
+    module_path: String,
+    token: Option<String>,
+    object: Option<String>,
+    id: Option<Vec<u8>>,
+    pin: Option<Zeroizing<String>>,
+}
+
+impl Uri {
+    fn parse(uri: &str) -> Result<Self, Box<dyn Error>> {
+        let rest = uri
+            .strip_prefix("pkcs11:")
+            .ok_or("PKCS#11 URI must start with 'pkcs11:'")?;
+        let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let path = attributes(path, ';')?;
+        let mut query = attributes(query, '&')?;
+
+        let pin = match (query.remove("pin-value"), query.remove("pin-source")) {
+            (Some(pin), _) => Some(Zeroizing::new(String::from_utf8(pin)?)),
+            (None, Some(source)) => {
+                let source = String::from_utf8(source)?;
+                let path = source.strip_prefix("file:").unwrap_or(&source);
+                let pin = Zeroizing::new(fs::read_to_string(path)?);
+                Some(Zeroizing::new(pin.trim_end().to_owned()))
+            }
+            (None, None) => None,
+        };
+        let text = |value: Option<Vec<u8>>| value.map(String::from_utf8).transpose();
+        Ok(Uri {
+            module_path: text(query.remove("module-path"))?
+                .ok_or("PKCS#11 URI needs a module-path")?,
+            token: text(path.get("token").cloned())?,
+            object: text(path.get("object").cloned())?,
+            id: path.get("id").cloned(),
+            pin,
+        })
+    }
+}
+
+/// Percent-decoded `name=value` pairs separated by `separator`.
+fn attributes(text: &str, separator: char) -> Result<HashMap<String, Vec<u8>>, Box<dyn Error>> {
+    text.split(separator)
+        .filter(|attribute| !attribute.is_empty())
+        .map(|attribute| {
+            let (name, value) = attribute
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid PKCS#11 URI attribute '{attribute}'"))?;
+            Ok((name.to_owned(), percent_decode(value)?))
+        })
+        .collect()
+}
+
+fn percent_decode(value: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut chars = value.bytes();
+    while let Some(byte) = chars.next() {
+        if byte == b'%' {
+            let hex = [chars.next().unwrap_or(0), chars.next().unwrap_or(0)];
+            let hex = std::str::from_utf8(&hex).map_err(|_| "Invalid percent encoding")?;
+            bytes.push(u8::from_str_radix(hex, 16).map_err(|_| "Invalid percent encoding")?);
+        } else {
+            bytes.push(byte);
+        }
+    }
+    Ok(bytes)
+}
+
+/// Logged in session with a PKCS#11 token and the AES key used to wrap data
+/// keys.
+pub struct Token {
+    session: Session,
+    key: ObjectHandle,
+}
+
+impl Token {
+    /// Connects to the token and key named by a `pkcs11:` URI, which needs the
+    /// `module-path` query attribute and at least one of `object` and `id`.
+    /// `pin` is used when the URI has neither `pin-value` nor `pin-source`.
+    pub fn connect(uri: &str, pin: Option<&str>) -> Result<Self, Box<dyn Error>> {
+        let uri = Uri::parse(uri)?;
+        if uri.object.is_none() && uri.id.is_none() {
+            Err("PKCS#11 URI needs an object or id to select the key")?
+        }
+        let pkcs11 = Pkcs11::new(&uri.module_path)?;
+        match pkcs11.initialize(CInitializeArgs::new(CInitializeFlags::OS_LOCKING_OK)) {
+            Err(Pkcs11Error::Pkcs11(RvError::CryptokiAlreadyInitialized, _)) | Ok(()) => {}
+            Err(e) => Err(e)?,
+        }
+
+        let mut slots = Vec::new();
+        for slot in pkcs11.get_slots_with_token()? {
+            let label = pkcs11.get_token_info(slot)?.label().to_owned();
+            if uri.token.as_ref().is_none_or(|token| *token == label) {
+                slots.push(slot);
+            }
+        }
+        let slot = match slots[..] {
+            [slot] => slot,
+            [] => Err("No matching PKCS#11 token")?,
+            _ => Err("Several PKCS#11 tokens match, add token= to the URI")?,
+        };
+
+        let session = pkcs11.open_ro_session(slot)?;
+        let pin = uri.pin.as_deref().map(String::as_str).or(pin);
+        session.login(UserType::User, pin.map(AuthPin::from).as_ref())?;
+
+        let mut template = vec![
+            Attribute::Class(ObjectClass::SECRET_KEY),
+            Attribute::KeyType(KeyType::AES),
+        ];
+        if let Some(object) = &uri.object {
+            template.push(Attribute::Label(object.as_bytes().to_vec()));
+        }
+        if let Some(id) = &uri.id {
+            template.push(Attribute::Id(id.clone()));
+        }
+        let key = match session.find_objects(&template)?[..] {
+            [key] => key,
+            [] => Err("No matching AES key on the PKCS#11 token")?,
+            _ => Err("Several keys on the PKCS#11 token match the URI")?,
+        };
+        Ok(Token { session, key })
+    }
+
+    /// Wraps `data_key` with AES-GCM on the token. `aad` binds the slot to
+    /// the image header.
+    pub fn seal(&self, data_key: &Key, aad: &[u8]) -> Result<KeySlot, Box<dyn Error>> {
+        let mut nonce = [0u8; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        let mut iv = nonce;
+        let params = GcmParams::new(&mut iv, aad, GCM_TAG_BITS.into())?;
+        let wrapped =
+            self.session
+                .encrypt(&Mechanism::AesGcm(params), self.key, &data_key.to_bytes())?;
+        Ok(KeySlot::wrapped(SlotKind::Token, nonce, wrapped))
+    }
+
+    /// Unwraps the data key of a token slot.
+    pub fn open(&self, slot: &KeySlot, aad: &[u8]) -> Result<Key, Box<dyn Error>> {
+        if slot.kind() != SlotKind::Token {
+            Err(format!("Key slot needs a {}", slot.kind()))?
+        }
+        let mut iv = *slot.nonce();
+        let params = GcmParams::new(&mut iv, aad, GCM_TAG_BITS.into())?;
+        let data_key = self
+            .session
+            .decrypt(&Mechanism::AesGcm(params), self.key, slot.wrapped_key())
+            .map_err(|_| "Wrong token key for key slot")?;
+        Key::from_bytes(&Zeroizing::new(data_key))
+    }
+}
+
+/// Recovers the data key of an image through its token slots.
+pub fn open(header: &Header, token: &Token) -> Result<Key, Box<dyn Error>> {
+    let aad = header.associated_data();
+    header
+        .key_slots
+        .iter()
+        .filter(|slot| slot.kind() == SlotKind::Token)
+        .find_map(|slot| token.open(slot, &aad).ok())
+        .ok_or_else(|| "No key slot opens with the given token key".into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn uri_test() {
+        let uri = Uri::parse(
+            "pkcs11:token=My%20Token;object=filegram;id=%01%02\
+             ?module-path=/usr/lib/softhsm/libsofthsm2.so&pin-value=1234",
+        )
+        .unwrap();
+
+        assert_eq!("/usr/lib/softhsm/libsofthsm2.so", uri.module_path);
+        assert_eq!(Some("My Token".to_owned()), uri.token);
+        assert_eq!(Some("filegram".to_owned()), uri.object);
+        assert_eq!(Some(vec![1, 2]), uri.id);
+        assert_eq!(Some("1234"), uri.pin.as_deref().map(String::as_str));
+        assert!(Uri::parse("pkcs11:object=filegram").is_err());
+        assert!(Uri::parse("file:object=filegram?module-path=x").is_err());
+    }
+}
@@ -0,0 +1,205 @@
+use std::{error::Error, fmt};
+
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use zeroize::Zeroizing;
+
+use super::Key;
+use crate::armor;
+
+const SHARE_LABEL: &str = "FILEGRAM KEY SHARE";
+const SHARE_VERSION: u8 = 1;
+const GROUP_SIZE: usize = 4;
+
+/// Multiplication in GF(2^8) with the AES polynomial, without data
+/// dependent branches.
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    for _ in 0..8 {
+        product ^= a & (b & 1).wrapping_neg();
+        let carry = (a >> 7).wrapping_neg();
+        a = (a << 1) ^ (0x1b & carry);
+        b >>= 1;
+    }
+    product
+}
+
+/// Multiplicative inverse, as a^254 = a^-1 in GF(2^8).
+fn gf_inv(a: u8) -> u8 {
+    let mut result = 1;
+    let mut base = a;
+    let mut exp = 254u8;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = gf_mul(result, base);
+        }
+        base = gf_mul(base, base);
+        exp >>= 1;
+    }
+    result
+}
+
+/// One of the shares a key is split into by [`split_key`].
+pub struct KeyShare {
+    /// random id shared by all shares of one key
+    group: [u8; GROUP_SIZE],
+    threshold: u8,
+    index: u8,
+    value: Zeroizing<Vec<u8>>,
+}
+
+impl fmt::Debug for KeyShare {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyShare")
+            .field("threshold", &self.threshold)
+            .field("index", &self.index)
+            .field("value", &"[REDACTED]")
+            .finish()
+    }
+}
+
+impl KeyShare {
+    pub fn threshold(&self) -> u8 {
+        self.threshold
+    }
+
+    pub fn index(&self) -> u8 {
+        self.index
+    }
+
+    pub fn to_armored(&self) -> Zeroizing<String> {
+        let mut body = Zeroizing::new(self.group.to_vec());
+        body.push(self.threshold);
+        body.push(self.index);
+        body.extend_from_slice(&self.value);
+        Zeroizing::new(armor::armor(SHARE_LABEL, SHARE_VERSION, &body))
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let text = std::str::from_utf8(data)?;
+        let (version, body) = armor::dearmor(SHARE_LABEL, text)?;
+        let body = Zeroizing::new(body);
+        if version != SHARE_VERSION {
+            Err(format!("Unsupported key share version {version}"))?
+        }
+        if body.len() <= GROUP_SIZE + 2 {
+            Err("Truncated key share")?
+        }
+        Ok(KeyShare {
+            group: body[..GROUP_SIZE].try_into()?,
+            threshold: body[GROUP_SIZE],
+            index: body[GROUP_SIZE + 1],
+            value: Zeroizing::new(body[GROUP_SIZE + 2..].to_vec()),
+        })
+    }
+}
+
+/// Splits `key` into `shares` Shamir shares, any `threshold` of which
+/// rebuild it with [`combine_shares`]. Fewer shares reveal nothing about it.
+pub fn split_key(key: &Key, threshold: u8, shares: u8) -> Result<Vec<KeyShare>, Box<dyn Error>> {
+    if threshold < 2 || threshold > shares {
+        Err("Threshold has to be between 2 and the number of shares")?
+    }
+    let mut group = [0u8; GROUP_SIZE];
+    OsRng.fill_bytes(&mut group);
+
+    let secret = key.to_bytes();
+    let mut values: Vec<Zeroizing<Vec<u8>>> = (0..shares)
+        .map(|_| Zeroizing::new(Vec::with_capacity(secret.len())))
+        .collect();
+    let mut coefficients = Zeroizing::new(vec![0u8; threshold as usize]);
+    for byte in secret.iter() {
+        coefficients[0] = *byte;
+        OsRng.fill_bytes(&mut coefficients[1..]);
+        for (i, value) in values.iter_mut().enumerate() {
+            let x = i as u8 + 1;
+            // Horner's scheme, highest coefficient first
+            let y = coefficients
+                .iter()
+                .rev()
+                .fold(0, |acc, coefficient| gf_mul(acc, x) ^ coefficient);
+            value.push(y);
+        }
+    }
+
+    Ok(values
+        .into_iter()
+        .enumerate()
+        .map(|(i, value)| KeyShare {
+            group,
+            threshold,
+            index: i as u8 + 1,
+            value,
+        })
+        .collect())
+}
+
+/// Rebuilds a key from at least `threshold` shares made by [`split_key`].
+pub fn combine_shares(shares: &[KeyShare]) -> Result<Key, Box<dyn Error>> {
+    let first = shares.first().ok_or("No key shares given")?;
+    if shares
+        .iter()
+        .any(|s| s.group != first.group || s.threshold != first.threshold)
+    {
+        Err("Key shares belong to different keys")?
+    }
+    let mut indices: Vec<u8> = shares.iter().map(|s| s.index).collect();
+    indices.sort_unstable();
+    indices.dedup();
+    if indices.len() != shares.len() || indices.contains(&0) {
+        Err("Duplicate or invalid key shares")?
+    }
+    if shares.len() < first.threshold as usize {
+        Err(format!(
+            "At least {} key shares are needed",
+            first.threshold
+        ))?
+    }
+    let shares = &shares[..first.threshold as usize];
+    let len = first.value.len();
+    if shares.iter().any(|s| s.value.len() != len) {
+        Err("Key shares have different lengths")?
+    }
+
+    // Lagrange basis polynomials evaluated at 0
+    let weights: Vec<u8> = shares
+        .iter()
+        .map(|share| {
+            shares
+                .iter()
+                .filter(|other| other.index != share.index)
+                .fold(1, |acc, other| {
+                    gf_mul(acc, gf_mul(other.index, gf_inv(other.index ^ share.index)))
+                })
+        })
+        .collect();
+    let secret: Zeroizing<Vec<u8>> = Zeroizing::new(
+        (0..len)
+            .map(|i| {
+                shares.iter().zip(&weights).fold(0, |acc, (share, weight)| {
+                    acc ^ gf_mul(share.value[i], *weight)
+                })
+            })
+            .collect(),
+    );
+    Key::from_bytes(&secret)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn split_combine_test() {
+        let key = Key::generate();
+        let shares = split_key(&key, 3, 5).unwrap();
+
+        for picked in [[0, 1, 2], [4, 2, 0], [1, 3, 4]] {
+            let picked: Vec<KeyShare> = picked
+                .iter()
+                .map(|&i| KeyShare::parse(shares[i].to_armored().as_bytes()).unwrap())
+                .collect();
+            assert_eq!(key, combine_shares(&picked).unwrap());
+        }
+        assert!(combine_shares(&shares[..2]).is_err());
+    }
+}
@@ -0,0 +1,121 @@
+use std::error::Error;
+
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+
+use crate::{padding::Bucket, tlv};
+
+const TAG_FILE_NAME: u8 = 1;
+const TAG_SIZE: u8 = 2;
+
+/// Descriptive metadata stored inside the encrypted payload in private mode,
+/// instead of the image header.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub file_name: Option<String>,
+    pub size: u64,
+}
+
+impl Metadata {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut fields = tlv::Writer::new();
+        if let Some(file_name) = &self.file_name {
+            fields.field(TAG_FILE_NAME, file_name.as_bytes());
+        }
+        fields.field(TAG_SIZE, &self.size.to_be_bytes());
+        fields.into_bytes()
+    }
+
+    fn from_bytes(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let mut metadata = Metadata::default();
+        let mut fields = tlv::Reader::new(data);
+        while let Some((tag, value)) = fields.next_field()? {
+            match tag {
+                TAG_FILE_NAME => metadata.file_name = Some(String::from_utf8(value.to_vec())?),
+                TAG_SIZE => metadata.size = tlv::read_u64(value)?,
+                _ => Err(format!("Unsupported metadata field {tag}"))?,
+            }
+        }
+        Ok(metadata)
+    }
+}
+
+/// Wraps `data` together with its metadata, to be encrypted as one payload.
+/// With a `bucket`, the envelope is padded so that only its size class is
+/// visible after encryption.
+pub fn seal(
+    file_name: Option<String>,
+    data: &[u8],
+    bucket: Option<Bucket>,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let metadata = Metadata {
+        file_name,
+        size: data.len() as u64,
+    }
+    .to_bytes();
+
+    let mut envelope = Vec::with_capacity(4 + metadata.len() + data.len());
+    envelope.extend_from_slice(&(metadata.len() as u32).to_be_bytes());
+    envelope.extend_from_slice(&metadata);
+    envelope.extend_from_slice(data);
+    if let Some(bucket) = bucket {
+        bucket.pad(&mut envelope)?;
+    }
+    Ok(envelope)
+}
+
+/// Length of the envelope [`seal`] wraps `len` bytes in, without a bucket.
+pub fn sealed_len(file_name: Option<String>, len: usize) -> usize {
+    let metadata = Metadata {
+        file_name,
+        size: len as u64,
+    };
+    4 + metadata.to_bytes().len() + len
+}
+
+/// Splits a decrypted envelope into metadata and data.
+pub fn open(mut envelope: Vec<u8>) -> Result<(Metadata, Vec<u8>), Box<dyn Error>> {
+    if envelope.len() < 4 {
+        Err("Truncated envelope")?
+    }
+    let metadata_len = tlv::read_u32(&envelope[..4])? as usize;
+    if envelope.len() - 4 < metadata_len {
+        Err("Truncated envelope")?
+    }
+    let metadata = Metadata::from_bytes(&envelope[4..4 + metadata_len])?;
+    let start = 4 + metadata_len;
+    let end = usize::try_from(metadata.size)
+        .ok()
+        .and_then(|size| start.checked_add(size))
+        .filter(|end| *end <= envelope.len())
+        .ok_or("Truncated envelope")?;
+    envelope.truncate(end);
+    envelope.drain(..start);
+    Ok((metadata, envelope))
+}
+
+/// Random name for images that shouldn't reveal what they hold.
+pub fn neutral_name() -> String {
+    let mut bytes = [0u8; 8];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn seal_open_test() {
+        for bucket in [None, Some(Bucket::PowerOfTwo), Some(Bucket::Fixed(1000))] {
+            let envelope = seal(Some("test.txt".to_owned()), b"filegram", bucket).unwrap();
+            if let Some(bucket) = bucket {
+                assert_eq!(bucket.padded_len(envelope.len()).unwrap(), envelope.len());
+            }
+            let (metadata, data) = open(envelope).unwrap();
+
+            assert_eq!(Some("test.txt".to_owned()), metadata.file_name);
+            assert_eq!(8, metadata.size);
+            assert_eq!(b"filegram".to_vec(), data);
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use std::{borrow::Cow, error::Error};
+
+use crate::{
+    deniable,
+    encryption::{Cipher, Key, TAG_SIZE},
+    envelope,
+    header::Header,
+    padding::Bucket,
+    pipeline::{Compress, Encrypt, Pad, Pipeline, Planar, Registry, Stage},
+};
+
+/// Payload written after the header, borrowed when no stage changed it.
+pub type Payload<'a> = Cow<'a, [u8]>;
+
+/// How a file is written to an image, the choices of `fig encode` and the
+/// web app.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Options {
+    pub encrypted: bool,
+    /// Keeps the file name and size inside the encrypted payload, see
+    /// [`envelope`]. Padding implies it.
+    pub private: bool,
+    pub compress: bool,
+    pub pad: Option<Bucket>,
+    pub planar: bool,
+}
+
+impl Options {
+    pub fn is_private(&self) -> bool {
+        self.private || self.pad.is_some()
+    }
+
+    /// Header and stages of the image of a file of `len` bytes named
+    /// `file_name`. Encrypted images get their cipher in
+    /// [`Encoding::forward`], after the key slots are sealed to the
+    /// [associated data](Header::associated_data) the stages are part of.
+    pub fn encoding(
+        &self,
+        file_name: Option<String>,
+        len: usize,
+    ) -> Result<Encoding, Box<dyn Error>> {
+        let private = self.is_private();
+        let mut pipeline = Pipeline::new();
+        if self.compress {
+            // the exact limit would give the size of private images away
+            let compress = match private {
+                true => Compress::default(),
+                false => Compress::default().with_limit(len)?,
+            };
+            pipeline = pipeline.with(compress);
+        }
+        if let Some(bucket) = self.pad {
+            pipeline = pipeline.with(Pad::new(bucket));
+        }
+        if self.planar {
+            pipeline = pipeline.with(Planar);
+        }
+        let mut header = Header {
+            encrypted: self.encrypted,
+            private,
+            stages: pipeline.stages(),
+            ..Header::new()
+        };
+        if self.encrypted {
+            header.stages.push(Stage::new(Encrypt::NAME));
+        }
+        // private images keep it in their envelope
+        match private {
+            true => Ok(Encoding {
+                header,
+                pipeline,
+                file_name,
+            }),
+            false => Ok(Encoding {
+                header: Header {
+                    file_name,
+                    ..header
+                },
+                pipeline,
+                file_name: None,
+            }),
+        }
+    }
+}
+
+/// Header and pipeline of an image being written, see
+/// [`Options::encoding`].
+pub struct Encoding {
+    pub header: Header,
+    pipeline: Pipeline,
+    /// File name sealed in the envelope of private images.
+    file_name: Option<String>,
+}
+
+impl Encoding {
+    /// Contents the cipher encrypts: `data` itself, or its envelope for
+    /// private images.
+    pub fn plaintext<'a>(&self, data: &'a [u8]) -> Result<Payload<'a>, Box<dyn Error>> {
+        match self.header.private {
+            true => Ok(envelope::seal(self.file_name.clone(), data, None)?.into()),
+            false => Ok(data.into()),
+        }
+    }
+
+    /// Most bytes the payload of a file of `len` bytes takes, without
+    /// running the stages, e.g. to plan the layout of its image.
+    pub fn max_payload_len(&self, len: usize) -> Result<usize, Box<dyn Error>> {
+        let len = match self.header.private {
+            true => envelope::sealed_len(self.file_name.clone(), len),
+            false => len,
+        };
+        let len = self.pipeline.max_forward_len(len);
+        // the cipher is only added by forward
+        let len = match self.header.encrypted {
+            true => len.and_then(|len| len.checked_add(TAG_SIZE)),
+            false => len,
+        };
+        Ok(len.ok_or("Payload too large")?)
+    }
+
+    /// Runs the stages over the [`plaintext`](Self::plaintext), encrypting
+    /// it with `cipher` last, and gives back the header with the payload.
+    /// Images without stages take `plaintext` as it is, e.g. a mapped file.
+    pub fn forward<'a>(
+        self,
+        plaintext: Payload<'a>,
+        cipher: Option<Cipher>,
+    ) -> Result<(Header, Payload<'a>), Box<dyn Error>> {
+        let pipeline = match (cipher, self.header.encrypted) {
+            (Some(cipher), true) => self.pipeline.with(Encrypt::new(cipher)),
+            (None, false) => self.pipeline,
+            (Some(_), false) => Err("A cipher was given for an image that isn't encrypted")?,
+            (None, true) => Err("Encrypted images need a cipher")?,
+        };
+        if self.header.stages.is_empty() {
+            return Ok((self.header, plaintext));
+        }
+        let payload = pipeline.forward(plaintext.into_owned(), &self.header.associated_data())?;
+        Ok((self.header, payload.into()))
+    }
+}
+
+/// Undoes the stages of `payload`, split off an image with `header`, and
+/// opens its envelope: the file name, if the image has one, and contents.
+/// `key` is the data key of encrypted images, see
+/// [`keyslot::open`](crate::keyslot::open). Images without a header carry
+/// no record of being encrypted, and are taken as encrypted when given one.
+pub fn open(
+    header: Option<Header>,
+    payload: Vec<u8>,
+    key: Option<Key>,
+) -> Result<(Option<String>, Vec<u8>), Box<dyn Error>> {
+    // images without a header were encrypted without associated data
+    let aad = header
+        .as_ref()
+        .map(Header::associated_data)
+        .unwrap_or_default();
+    let legacy = header.is_none();
+    let mut header = header.unwrap_or_default();
+    if header.deniable {
+        Err("Image is deniable, open it with a passphrase")?
+    }
+    let mut registry = Registry::new();
+    if let Some(key) = key {
+        header.encrypted |= legacy;
+        registry.with_key(key);
+    }
+    let data = Pipeline::from_header(&header, &registry)?.inverse(payload, &aad)?;
+    match header.private {
+        true => {
+            let (metadata, data) = envelope::open(data)?;
+            Ok((metadata.file_name, data))
+        }
+        false => Ok((header.file_name, data)),
+    }
+}
+
+/// [`open`] for deniable images, whose layers open with a passphrase.
+pub fn open_deniable(
+    header: &Header,
+    payload: &[u8],
+    passphrase: &str,
+) -> Result<(Option<String>, Vec<u8>), Box<dyn Error>> {
+    let data = deniable::open(payload, passphrase, &header.associated_data())?;
+    let (metadata, data) = envelope::open(data)?;
+    Ok((metadata.file_name, data))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{decode, encode, keyslot::KeySlot, keyslot::Secret};
+
+    #[test]
+    fn encoding_open_test() {
+        let data = b"filegram ".repeat(1000);
+        let name = Some("test.txt".to_owned());
+
+        let options = Options {
+            compress: true,
+            planar: true,
+            ..Options::default()
+        };
+        let encoding = options.encoding(name.clone(), data.len()).unwrap();
+        let plaintext = encoding.plaintext(&data).unwrap();
+        let (header, payload) = encoding.forward(plaintext, None).unwrap();
+        assert_eq!(name, header.file_name);
+        assert!(payload.len() < data.len());
+        let encoding = options.encoding(name.clone(), data.len()).unwrap();
+        assert!(encoding.max_payload_len(data.len()).unwrap() >= payload.len());
+        let image = encode::with_header(&header, &payload);
+        let (header, payload) = decode::split_header(&image).unwrap();
+        assert_eq!(
+            (name.clone(), data.clone()),
+            open(header, payload, None).unwrap()
+        );
+
+        let options = Options {
+            encrypted: true,
+            pad: Some(Bucket::Fixed(16384)),
+            ..Options::default()
+        };
+        let mut encoding = options.encoding(name.clone(), data.len()).unwrap();
+        let cipher = Cipher::new();
+        let key = Key::from_bytes(&cipher.key().to_bytes()).unwrap();
+        let aad = encoding.header.associated_data();
+        let slot = KeySlot::seal(cipher.key(), &Secret::Key(key.copy()), &aad).unwrap();
+        encoding.header.key_slots.push(slot);
+        let plaintext = encoding.plaintext(&data).unwrap();
+        assert!(options
+            .encoding(None, 0)
+            .unwrap()
+            .forward(plaintext.clone(), None)
+            .is_err());
+        let (header, payload) = encoding.forward(plaintext, Some(cipher)).unwrap();
+        assert_eq!((None, true), (header.file_name.clone(), header.private));
+        assert_eq!(16384 + 16, payload.len());
+        let encoding = options.encoding(name.clone(), data.len()).unwrap();
+        assert_eq!(payload.len(), encoding.max_payload_len(data.len()).unwrap());
+        let image = encode::with_header(&header, &payload);
+        let (header, payload) = decode::split_header(&image).unwrap();
+        assert!(open(header.clone(), payload.clone(), None).is_err());
+        assert_eq!((name, data), open(header, payload, Some(key)).unwrap());
+    }
+}
@@ -0,0 +1,208 @@
+use std::error::Error;
+
+use filegram_core::header::PREFIX_SIZE;
+
+use crate::{keyslot::KeySlot, pipeline::Stage, tlv};
+
+pub use filegram_core::header::{FORMAT_VERSION, MAGIC};
+
+const FLAG_ENCRYPTED: u8 = 0b0000_0001;
+const FLAG_PRIVATE: u8 = 0b0000_0010;
+const FLAG_DENIABLE: u8 = 0b0000_0100;
+const FLAGS: u8 = FLAG_ENCRYPTED | FLAG_PRIVATE | FLAG_DENIABLE;
+
+const TAG_FILE_NAME: u8 = 1;
+const TAG_PART: u8 = 2;
+/// key slots without the ID of their key, as first written
+const TAG_KEY_SLOT_WITHOUT_ID: u8 = 3;
+const TAG_STAGE: u8 = 4;
+const TAG_BANNER: u8 = 5;
+const TAG_KEY_SLOT: u8 = 6;
+
+/// Most banner rows a header may reserve, far more than a
+/// [`Label`](crate::label::Label) takes.
+const MAX_BANNER_ROWS: u32 = 1024;
+
+/// Unencrypted metadata stored in the first rows of an image.
+///
+/// When the payload is encrypted, the serialized header is passed to the
+/// cipher as associated data, so changing any of its bytes makes decryption
+/// fail. Key slots are the exception, as they can be edited without the
+/// payload and are authenticated on their own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub encrypted: bool,
+    /// payload is an [`envelope`](crate::envelope) carrying the descriptive
+    /// metadata, which is left out of the header
+    pub private: bool,
+    /// payload is split into a decoy and a possibly hidden layer, see
+    /// [`deniable`](crate::deniable)
+    pub deniable: bool,
+    pub file_name: Option<String>,
+    /// index of this image among `parts` images holding one file
+    pub part: u32,
+    pub parts: u32,
+    pub key_slots: Vec<KeySlot>,
+    /// transforms applied to the payload, in order, see
+    /// [`Pipeline`](crate::pipeline::Pipeline)
+    pub stages: Vec<Stage>,
+    /// pixel rows of a [`Label`](crate::label::Label) banner between the
+    /// header and the payload, 0 without one
+    pub banner_rows: u32,
+}
+
+impl Default for Header {
+    fn default() -> Self {
+        Header {
+            encrypted: false,
+            private: false,
+            deniable: false,
+            file_name: None,
+            part: 0,
+            parts: 1,
+            key_slots: Vec::new(),
+            stages: Vec::new(),
+            banner_rows: 0,
+        }
+    }
+}
+
+impl Header {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut fields = tlv::Writer::new();
+        if let Some(file_name) = &self.file_name {
+            fields.field(TAG_FILE_NAME, file_name.as_bytes());
+        }
+        let mut part = self.part.to_be_bytes().to_vec();
+        part.extend_from_slice(&self.parts.to_be_bytes());
+        fields.field(TAG_PART, &part);
+        for stage in &self.stages {
+            fields.field(TAG_STAGE, &stage.to_bytes());
+        }
+        if self.banner_rows != 0 {
+            fields.field(TAG_BANNER, &self.banner_rows.to_be_bytes());
+        }
+        for slot in &self.key_slots {
+            fields.field(TAG_KEY_SLOT, &slot.to_bytes());
+        }
+        let fields = fields.into_bytes();
+
+        let mut flags = 0;
+        if self.encrypted {
+            flags |= FLAG_ENCRYPTED;
+        }
+        if self.private {
+            flags |= FLAG_PRIVATE;
+        }
+        if self.deniable {
+            flags |= FLAG_DENIABLE;
+        }
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(FORMAT_VERSION);
+        bytes.push(flags);
+        bytes.extend_from_slice(&(fields.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&fields);
+        bytes
+    }
+
+    /// Associated data binding the header to an encrypted payload.
+    pub fn associated_data(&self) -> Vec<u8> {
+        Header {
+            key_slots: Vec::new(),
+            ..self.clone()
+        }
+        .to_bytes()
+    }
+
+    pub fn is_present(data: &[u8]) -> bool {
+        filegram_core::header::is_present(data)
+    }
+
+    /// Number of bytes taken by the header starting `data`, which only needs
+    /// to hold the fixed size prefix.
+    pub fn encoded_len(data: &[u8]) -> Result<usize, Box<dyn Error>> {
+        Ok(filegram_core::header::encoded_len(data)?)
+    }
+
+    /// Parses a header from the start of `data`, returning it together with
+    /// the number of bytes it occupies.
+    pub fn from_bytes(data: &[u8]) -> Result<(Self, usize), Box<dyn Error>> {
+        let end = Self::encoded_len(data)?;
+        let version = data[MAGIC.len()];
+        if version != FORMAT_VERSION {
+            Err(format!("Unsupported format version {version}"))?
+        }
+        let flags = data[MAGIC.len() + 1];
+        if flags & !FLAGS != 0 {
+            Err("Unsupported header flags")?
+        }
+        if data.len() < end {
+            Err("Truncated header")?
+        }
+
+        let mut header = Header {
+            encrypted: flags & FLAG_ENCRYPTED != 0,
+            private: flags & FLAG_PRIVATE != 0,
+            deniable: flags & FLAG_DENIABLE != 0,
+            ..Header::default()
+        };
+        let mut fields = tlv::Reader::new(&data[PREFIX_SIZE..end]);
+        while let Some((tag, value)) = fields.next_field()? {
+            match tag {
+                TAG_FILE_NAME => header.file_name = Some(String::from_utf8(value.to_vec())?),
+                TAG_PART => {
+                    if value.len() != 8 {
+                        Err("Invalid part field")?
+                    }
+                    header.part = tlv::read_u32(&value[..4])?;
+                    header.parts = tlv::read_u32(&value[4..])?;
+                }
+                TAG_KEY_SLOT => header.key_slots.push(KeySlot::from_bytes(value)?),
+                TAG_KEY_SLOT_WITHOUT_ID => header
+                    .key_slots
+                    .push(KeySlot::from_bytes_without_key_id(value)?),
+                TAG_STAGE => header.stages.push(Stage::from_bytes(value)?),
+                TAG_BANNER => {
+                    header.banner_rows = tlv::read_u32(value)?;
+                    if header.banner_rows > MAX_BANNER_ROWS {
+                        Err("Header reserves too many banner rows")?
+                    }
+                }
+                _ => Err(format!("Unsupported header field {tag}"))?,
+            }
+        }
+        Ok((header, end))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn header_bytes_test() {
+        let header = Header {
+            encrypted: true,
+            file_name: Some("test.txt".to_owned()),
+            part: 1,
+            parts: 3,
+            stages: vec![Stage::new("deflate")],
+            banner_rows: 30,
+            ..Header::new()
+        };
+        let bytes = header.to_bytes();
+
+        assert_eq!((header, bytes.len()), Header::from_bytes(&bytes).unwrap());
+
+        let huge = Header {
+            banner_rows: u32::MAX,
+            ..Header::new()
+        };
+        assert!(Header::from_bytes(&huge.to_bytes()).is_err());
+    }
+}
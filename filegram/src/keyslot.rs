@@ -0,0 +1,464 @@
+use std::{error::Error, fmt};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, OsRng, Payload},
+    ChaCha20Poly1305, KeyInit,
+};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+use crate::{
+    encryption::{Key, KeyId, KEY_ID_SIZE, KEY_SIZE, NONCE_SIZE},
+    header::Header,
+    pipeline::{Pipeline, Registry},
+    tlv,
+};
+
+const SALT_SIZE: usize = 16;
+const KDF_PARAMS_SIZE: usize = 12;
+const RECOVERY_CODE_SIZE: usize = 16;
+const CONVERGENT_SLOT_INFO: &[u8] = b"filegram convergent slot v1";
+
+/// What unlocks a key slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotKind {
+    KeyFile,
+    Passphrase,
+    RecoveryCode,
+    MasterKey,
+    /// AES key on a PKCS#11 token, see
+    /// [`Token`](crate::encryption::pkcs11::Token) with the `pkcs11` feature
+    Token,
+}
+
+impl SlotKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            SlotKind::KeyFile => 1,
+            SlotKind::Passphrase => 2,
+            SlotKind::RecoveryCode => 3,
+            SlotKind::MasterKey => 4,
+            SlotKind::Token => 5,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<Self, Box<dyn Error>> {
+        match byte {
+            1 => Ok(SlotKind::KeyFile),
+            2 => Ok(SlotKind::Passphrase),
+            3 => Ok(SlotKind::RecoveryCode),
+            4 => Ok(SlotKind::MasterKey),
+            5 => Ok(SlotKind::Token),
+            _ => Err(format!("Unsupported key slot kind {byte}"))?,
+        }
+    }
+}
+
+impl fmt::Display for SlotKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SlotKind::KeyFile => f.write_str("key file"),
+            SlotKind::Passphrase => f.write_str("passphrase"),
+            SlotKind::RecoveryCode => f.write_str("recovery code"),
+            SlotKind::MasterKey => f.write_str("master key"),
+            SlotKind::Token => f.write_str("token key"),
+        }
+    }
+}
+
+/// Secret used to create or open a key slot.
+pub enum Secret {
+    Key(Key),
+    Passphrase(Zeroizing<String>),
+    RecoveryCode(Zeroizing<String>),
+    /// key that opens every image it was used for, through a per-file key
+    /// derived from the slot salt
+    MasterKey(Key),
+}
+
+impl Secret {
+    pub fn kind(&self) -> SlotKind {
+        match self {
+            Secret::Key(_) => SlotKind::KeyFile,
+            Secret::Passphrase(_) => SlotKind::Passphrase,
+            Secret::RecoveryCode(_) => SlotKind::RecoveryCode,
+            Secret::MasterKey(_) => SlotKind::MasterKey,
+        }
+    }
+
+    /// ID of a key file or master key, to find it again in a keyring.
+    pub fn key_id(&self) -> Option<KeyId> {
+        match self {
+            Secret::Key(key) | Secret::MasterKey(key) => Some(key.id()),
+            Secret::Passphrase(_) | Secret::RecoveryCode(_) => None,
+        }
+    }
+}
+
+/// Argon2id cost parameters, stored per slot so they can be raised later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct KdfParams {
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+}
+
+impl KdfParams {
+    /// Highest costs accepted from an image, far above the defaults, so a
+    /// crafted slot can't make opening it take all memory or forever:
+    /// 1 GiB, in KiB, 10 passes and 16 lanes.
+    const MAX: KdfParams = KdfParams {
+        m_cost: 1024 * 1024,
+        t_cost: 10,
+        p_cost: 16,
+    };
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let kdf = KdfParams {
+            m_cost: tlv::read_u32(&bytes[..4])?,
+            t_cost: tlv::read_u32(&bytes[4..8])?,
+            p_cost: tlv::read_u32(&bytes[8..])?,
+        };
+        let max = Self::MAX;
+        if kdf.m_cost > max.m_cost || kdf.t_cost > max.t_cost || kdf.p_cost > max.p_cost {
+            Err("Key slot asks for too costly key derivation")?
+        }
+        Ok(kdf)
+    }
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams {
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+/// Copy of an image's data key, encrypted with a key derived from one secret.
+///
+/// Slots live in the image header but are left out of its associated data,
+/// so they can be added and removed without touching the payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeySlot {
+    kind: SlotKind,
+    key_id: Option<KeyId>,
+    salt: [u8; SALT_SIZE],
+    kdf: KdfParams,
+    nonce: [u8; NONCE_SIZE],
+    wrapped: Vec<u8>,
+}
+
+impl KeySlot {
+    pub fn kind(&self) -> SlotKind {
+        self.kind
+    }
+
+    /// ID of the key file or master key that opens this slot.
+    pub fn key_id(&self) -> Option<KeyId> {
+        self.key_id
+    }
+
+    /// Encrypts `data_key` so that `secret` can recover it. `aad` binds the
+    /// slot to the image header.
+    pub fn seal(data_key: &Key, secret: &Secret, aad: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let mut salt = [0u8; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+        let mut nonce = [0u8; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        Self::seal_with(data_key, secret, aad, salt, nonce)
+    }
+
+    /// Master key slot for a [convergent](Key::convergent) data key, with the
+    /// salt and nonce derived from the data key so equal images get equal
+    /// slots. Each data key gets its own wrapping key, so the fixed nonce is
+    /// never reused with another key.
+    pub fn seal_convergent(data_key: &Key, team: &Key, aad: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let hkdf = Hkdf::<Sha256>::new(None, &data_key.to_bytes());
+        let mut bytes = [0u8; SALT_SIZE + NONCE_SIZE];
+        // the output is far below the HKDF-SHA256 limit of 8160 bytes
+        hkdf.expand(CONVERGENT_SLOT_INFO, &mut bytes).unwrap();
+        let (salt, nonce) = bytes.split_at(SALT_SIZE);
+        let secret = Secret::MasterKey(team.copy());
+        Self::seal_with(data_key, &secret, aad, salt.try_into()?, nonce.try_into()?)
+    }
+
+    fn seal_with(
+        data_key: &Key,
+        secret: &Secret,
+        aad: &[u8],
+        salt: [u8; SALT_SIZE],
+        nonce: [u8; NONCE_SIZE],
+    ) -> Result<Self, Box<dyn Error>> {
+        let kdf = KdfParams::default();
+
+        let wrapping_key = wrapping_key(secret, &salt, &kdf)?;
+        let cipher = ChaCha20Poly1305::new(wrapping_key.as_ref().into());
+        let payload = Payload {
+            msg: &data_key.to_bytes(),
+            aad,
+        };
+        let wrapped = cipher
+            .encrypt(&nonce.into(), payload)
+            .map_err(|_| "Couldn't seal key slot")?;
+        Ok(KeySlot {
+            kind: secret.kind(),
+            key_id: secret.key_id(),
+            salt,
+            kdf,
+            nonce,
+            wrapped,
+        })
+    }
+
+    /// Slot whose data key was wrapped outside filegram, e.g. on a token.
+    #[cfg_attr(not(feature = "pkcs11"), allow(dead_code))]
+    pub(crate) fn wrapped(kind: SlotKind, nonce: [u8; NONCE_SIZE], wrapped: Vec<u8>) -> Self {
+        KeySlot {
+            kind,
+            key_id: None,
+            salt: [0; SALT_SIZE],
+            kdf: KdfParams::default(),
+            nonce,
+            wrapped,
+        }
+    }
+
+    #[cfg_attr(not(feature = "pkcs11"), allow(dead_code))]
+    pub(crate) fn nonce(&self) -> &[u8; NONCE_SIZE] {
+        &self.nonce
+    }
+
+    #[cfg_attr(not(feature = "pkcs11"), allow(dead_code))]
+    pub(crate) fn wrapped_key(&self) -> &[u8] {
+        &self.wrapped
+    }
+
+    /// Recovers the data key, failing if `secret` doesn't match this slot.
+    pub fn open(&self, secret: &Secret, aad: &[u8]) -> Result<Key, Box<dyn Error>> {
+        if secret.kind() != self.kind {
+            Err(format!("Key slot needs a {}", self.kind))?
+        }
+        let wrapping_key = wrapping_key(secret, &self.salt, &self.kdf)?;
+        let cipher = ChaCha20Poly1305::new(wrapping_key.as_ref().into());
+        let payload = Payload {
+            msg: &self.wrapped,
+            aad,
+        };
+        let data_key = cipher
+            .decrypt(&self.nonce.into(), payload)
+            .map_err(|_| format!("Wrong {} for key slot", self.kind))?;
+        Key::from_bytes(&Zeroizing::new(data_key))
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.kind.to_byte()];
+        // all zero for slots opened by passphrases and recovery codes
+        let key_id = self.key_id.map(|id| *id.as_bytes()).unwrap_or_default();
+        bytes.extend_from_slice(&key_id);
+        bytes.extend_from_slice(&self.salt);
+        bytes.extend_from_slice(&self.kdf.m_cost.to_be_bytes());
+        bytes.extend_from_slice(&self.kdf.t_cost.to_be_bytes());
+        bytes.extend_from_slice(&self.kdf.p_cost.to_be_bytes());
+        bytes.extend_from_slice(&self.nonce);
+        bytes.extend_from_slice(&self.wrapped);
+        bytes
+    }
+
+    /// Parses a slot written by [`to_bytes`](Self::to_bytes).
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        Self::parse(bytes, true)
+    }
+
+    /// Parses a slot written before slots recorded the ID of their key,
+    /// which is the same without it.
+    pub(crate) fn from_bytes_without_key_id(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        Self::parse(bytes, false)
+    }
+
+    fn parse(bytes: &[u8], with_key_id: bool) -> Result<Self, Box<dyn Error>> {
+        let key_id_size = if with_key_id { KEY_ID_SIZE } else { 0 };
+        let fixed = 1 + key_id_size + SALT_SIZE + KDF_PARAMS_SIZE + NONCE_SIZE;
+        if bytes.len() <= fixed {
+            Err("Truncated key slot")?
+        }
+        let kind = SlotKind::from_byte(bytes[0])?;
+        let (key_id, rest) = bytes[1..].split_at(key_id_size);
+        let key_id = match with_key_id {
+            true => Some(KeyId::from_bytes(key_id.try_into()?)),
+            false => None,
+        };
+        let (salt, rest) = rest.split_at(SALT_SIZE);
+        let (kdf, rest) = rest.split_at(KDF_PARAMS_SIZE);
+        let (nonce, wrapped) = rest.split_at(NONCE_SIZE);
+        Ok(KeySlot {
+            kind,
+            key_id: key_id.filter(|id| *id.as_bytes() != [0; KEY_ID_SIZE]),
+            salt: salt.try_into()?,
+            kdf: KdfParams::from_bytes(kdf)?,
+            nonce: nonce.try_into()?,
+            wrapped: wrapped.to_vec(),
+        })
+    }
+}
+
+fn wrapping_key(
+    secret: &Secret,
+    salt: &[u8],
+    kdf: &KdfParams,
+) -> Result<Zeroizing<[u8; KEY_SIZE]>, Box<dyn Error>> {
+    let mut key = Zeroizing::new([0u8; KEY_SIZE]);
+    let input = match secret {
+        Secret::Key(key_file) => {
+            key.copy_from_slice(key_file.secret().expose());
+            return Ok(key);
+        }
+        Secret::MasterKey(master) => {
+            key.copy_from_slice(master.derive(salt).secret().expose());
+            return Ok(key);
+        }
+        Secret::Passphrase(passphrase) => Zeroizing::new(passphrase.as_bytes().to_vec()),
+        Secret::RecoveryCode(code) => normalize_recovery_code(code),
+    };
+    let params = Params::new(kdf.m_cost, kdf.t_cost, kdf.p_cost, Some(KEY_SIZE))
+        .map_err(|e| format!("Invalid key slot parameters: {e}"))?;
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(&input, salt, key.as_mut())
+        .map_err(|e| format!("Couldn't derive key slot key: {e}"))?;
+    Ok(key)
+}
+
+/// Random recovery code, printed as groups of four hex digits.
+pub fn generate_recovery_code() -> Zeroizing<String> {
+    let mut bytes = Zeroizing::new([0u8; RECOVERY_CODE_SIZE]);
+    OsRng.fill_bytes(bytes.as_mut());
+    let hex = Zeroizing::new(bytes.iter().map(|b| format!("{b:02x}")).collect::<String>());
+    let groups: Vec<&str> = (0..hex.len()).step_by(4).map(|i| &hex[i..i + 4]).collect();
+    Zeroizing::new(groups.join("-"))
+}
+
+/// Recovery codes are accepted regardless of case, spacing and dashes.
+fn normalize_recovery_code(code: &str) -> Zeroizing<Vec<u8>> {
+    Zeroizing::new(
+        code.chars()
+            .filter(char::is_ascii_alphanumeric)
+            .map(|c| c.to_ascii_lowercase() as u8)
+            .collect(),
+    )
+}
+
+/// Recovers the data key of an image from `secret`: through its key slots,
+/// or directly for images encrypted before key slots existed.
+pub fn open(header: &Header, secret: &Secret) -> Result<Key, Box<dyn Error>> {
+    if header.key_slots.is_empty() {
+        return match secret {
+            Secret::Key(key) => Ok(key.copy()),
+            _ => Err("Image has no key slots, a key file is required")?,
+        };
+    }
+    let aad = header.associated_data();
+    header
+        .key_slots
+        .iter()
+        .filter(|slot| slot.kind == secret.kind())
+        .find_map(|slot| slot.open(secret, &aad).ok())
+        .ok_or_else(|| format!("No key slot opens with the given {}", secret.kind()).into())
+}
+
+/// Fails unless `key` decrypts `payload`, the payload of the image with
+/// `header`. Images without key slots open with any key given, see
+/// [`open`], so this is the only check of it.
+pub fn check_key(header: &Header, key: &Key, payload: Vec<u8>) -> Result<(), Box<dyn Error>> {
+    let mut registry = Registry::new();
+    registry.with_key(key.copy());
+    Pipeline::from_header(header, &registry)?
+        .inverse(payload, &header.associated_data())
+        .map_err(|_| "The key doesn't open the image")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::encryption::Cipher;
+
+    #[test]
+    fn key_slots_test() {
+        let data_key = Key::generate();
+        let key_file = Key::generate();
+        let code = generate_recovery_code();
+        let master = Key::generate();
+        let passphrase = || Secret::Passphrase("correct horse battery staple".to_owned().into());
+        let mut header = Header {
+            encrypted: true,
+            ..Header::new()
+        };
+        let aad = header.associated_data();
+        for secret in [
+            Secret::Key(key_file.copy()),
+            passphrase(),
+            Secret::RecoveryCode(code.clone()),
+            Secret::MasterKey(master.copy()),
+        ] {
+            header
+                .key_slots
+                .push(KeySlot::seal(&data_key, &secret, &aad).unwrap());
+        }
+
+        let bytes = header.to_bytes();
+        let (header, _) = Header::from_bytes(&bytes).unwrap();
+        assert_eq!(aad, header.associated_data());
+        assert_eq!(Some(key_file.id()), header.key_slots[0].key_id());
+        assert_eq!(None, header.key_slots[1].key_id());
+
+        let typed_code = code.to_uppercase().replace('-', " ");
+        for secret in [
+            Secret::Key(key_file),
+            passphrase(),
+            Secret::RecoveryCode(typed_code.into()),
+            Secret::MasterKey(master),
+        ] {
+            assert_eq!(data_key, open(&header, &secret).unwrap());
+        }
+        let team = Key::generate();
+        let slot = KeySlot::seal_convergent(&data_key, &team, &aad).unwrap();
+        assert_eq!(
+            slot,
+            KeySlot::seal_convergent(&data_key, &team, &aad).unwrap()
+        );
+        assert_eq!(data_key, slot.open(&Secret::MasterKey(team), &aad).unwrap());
+
+        let wrong = Secret::Passphrase("wrong".to_owned().into());
+        assert!(open(&header, &wrong).is_err());
+        assert!(open(&header, &Secret::Key(Key::generate())).is_err());
+
+        // costs from the image are bounded before anything is derived
+        let mut bytes = header.key_slots[1].to_bytes();
+        let m_cost = 1 + KEY_ID_SIZE + SALT_SIZE;
+        bytes[m_cost..m_cost + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(KeySlot::from_bytes(&bytes).is_err());
+
+        // slots written before they had key IDs still open
+        let mut bytes = header.key_slots[1].to_bytes();
+        bytes.drain(1..1 + KEY_ID_SIZE);
+        let slot = KeySlot::from_bytes_without_key_id(&bytes).unwrap();
+        assert_eq!(header.key_slots[1], slot);
+        assert_eq!(data_key, slot.open(&passphrase(), &aad).unwrap());
+
+        // images without slots take any key file, checked on the payload
+        let legacy = Header {
+            encrypted: true,
+            ..Header::new()
+        };
+        let aad = legacy.associated_data();
+        let payload = Cipher::load(&data_key).encrypt(b"filegram", &aad);
+        let wrong = open(&legacy, &Secret::Key(Key::generate())).unwrap();
+        assert!(check_key(&legacy, &wrong, payload.clone()).is_err());
+        let key = open(&legacy, &Secret::Key(data_key)).unwrap();
+        check_key(&legacy, &key, payload).unwrap();
+    }
+}
@@ -0,0 +1,238 @@
+use std::{
+    ops::Range,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use image::{Rgb, RgbImage};
+
+/// Glyphs are 3 pixels wide and 5 high, with a column between characters.
+const GLYPH_WIDTH: u32 = 3;
+const GLYPH_HEIGHT: u32 = 5;
+const ADVANCE: u32 = GLYPH_WIDTH + 1;
+const LINE_HEIGHT: u32 = GLYPH_HEIGHT + 2;
+const MARGIN: u32 = 2;
+const LINES: u32 = 4;
+
+const BACKGROUND: Rgb<u8> = Rgb([255, 255, 255]);
+const INK: Rgb<u8> = Rgb([0, 0, 0]);
+
+/// Uppercase letters, digits and some punctuation, each row of a glyph a
+/// 3 bit mask with the leftmost pixel in the highest bit. Other characters
+/// are drawn as `?`.
+const FONT: &[(char, [u8; 5])] = &[
+    ('A', [0b010, 0b101, 0b111, 0b101, 0b101]),
+    ('B', [0b110, 0b101, 0b110, 0b101, 0b110]),
+    ('C', [0b011, 0b100, 0b100, 0b100, 0b011]),
+    ('D', [0b110, 0b101, 0b101, 0b101, 0b110]),
+    ('E', [0b111, 0b100, 0b110, 0b100, 0b111]),
+    ('F', [0b111, 0b100, 0b110, 0b100, 0b100]),
+    ('G', [0b011, 0b100, 0b101, 0b101, 0b011]),
+    ('H', [0b101, 0b101, 0b111, 0b101, 0b101]),
+    ('I', [0b111, 0b010, 0b010, 0b010, 0b111]),
+    ('J', [0b001, 0b001, 0b001, 0b101, 0b010]),
+    ('K', [0b101, 0b101, 0b110, 0b101, 0b101]),
+    ('L', [0b100, 0b100, 0b100, 0b100, 0b111]),
+    ('M', [0b101, 0b111, 0b111, 0b101, 0b101]),
+    ('N', [0b110, 0b101, 0b101, 0b101, 0b101]),
+    ('O', [0b010, 0b101, 0b101, 0b101, 0b010]),
+    ('P', [0b110, 0b101, 0b110, 0b100, 0b100]),
+    ('Q', [0b010, 0b101, 0b101, 0b110, 0b011]),
+    ('R', [0b110, 0b101, 0b110, 0b101, 0b101]),
+    ('S', [0b011, 0b100, 0b010, 0b001, 0b110]),
+    ('T', [0b111, 0b010, 0b010, 0b010, 0b010]),
+    ('U', [0b101, 0b101, 0b101, 0b101, 0b111]),
+    ('V', [0b101, 0b101, 0b101, 0b101, 0b010]),
+    ('W', [0b101, 0b101, 0b111, 0b111, 0b101]),
+    ('X', [0b101, 0b101, 0b010, 0b101, 0b101]),
+    ('Y', [0b101, 0b101, 0b010, 0b010, 0b010]),
+    ('Z', [0b111, 0b001, 0b010, 0b100, 0b111]),
+    ('0', [0b111, 0b101, 0b101, 0b101, 0b111]),
+    ('1', [0b010, 0b110, 0b010, 0b010, 0b111]),
+    ('2', [0b110, 0b001, 0b010, 0b100, 0b111]),
+    ('3', [0b110, 0b001, 0b010, 0b001, 0b110]),
+    ('4', [0b101, 0b101, 0b111, 0b001, 0b001]),
+    ('5', [0b111, 0b100, 0b110, 0b001, 0b110]),
+    ('6', [0b011, 0b100, 0b111, 0b101, 0b111]),
+    ('7', [0b111, 0b001, 0b010, 0b010, 0b010]),
+    ('8', [0b111, 0b101, 0b111, 0b101, 0b111]),
+    ('9', [0b111, 0b101, 0b111, 0b001, 0b110]),
+    (' ', [0b000, 0b000, 0b000, 0b000, 0b000]),
+    ('.', [0b000, 0b000, 0b000, 0b000, 0b010]),
+    (',', [0b000, 0b000, 0b000, 0b010, 0b100]),
+    ('-', [0b000, 0b000, 0b111, 0b000, 0b000]),
+    ('_', [0b000, 0b000, 0b000, 0b000, 0b111]),
+    (':', [0b000, 0b010, 0b000, 0b010, 0b000]),
+    ('/', [0b001, 0b001, 0b010, 0b100, 0b100]),
+    ('(', [0b001, 0b010, 0b010, 0b010, 0b001]),
+    (')', [0b100, 0b010, 0b010, 0b010, 0b100]),
+    ('[', [0b011, 0b010, 0b010, 0b010, 0b011]),
+    (']', [0b110, 0b010, 0b010, 0b010, 0b110]),
+    ('+', [0b000, 0b010, 0b111, 0b010, 0b000]),
+    ('=', [0b000, 0b111, 0b000, 0b111, 0b000]),
+    ('#', [0b101, 0b111, 0b101, 0b111, 0b101]),
+    ('\'', [0b010, 0b010, 0b000, 0b000, 0b000]),
+    ('!', [0b010, 0b010, 0b010, 0b000, 0b010]),
+    ('?', [0b110, 0b001, 0b010, 0b000, 0b010]),
+];
+
+fn glyph(c: char) -> [u8; 5] {
+    let find = |c: char| FONT.iter().find(|(other, _)| *other == c);
+    find(c.to_ascii_uppercase())
+        .or_else(|| find('?'))
+        .map(|(_, glyph)| *glyph)
+        .expect("font has a ? glyph")
+}
+
+/// Description of a file drawn in a banner above the payload of an image,
+/// for people who come across the image without knowing what it holds.
+/// Images record the banner in [`Header::banner_rows`](crate::header::Header::banner_rows),
+/// so decoders skip it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Label {
+    /// left out for private images, whose header doesn't show it either
+    pub file_name: Option<String>,
+    pub size: Option<u64>,
+    pub date: Option<SystemTime>,
+}
+
+impl Label {
+    /// Pixel rows of every banner, whatever the width of the image.
+    pub const ROWS: u32 = 2 * MARGIN + LINES * LINE_HEIGHT - (LINE_HEIGHT - GLYPH_HEIGHT);
+
+    /// Lines of the banner, before they're cut to its width.
+    pub fn lines(&self) -> Vec<String> {
+        vec![
+            self.file_name
+                .clone()
+                .unwrap_or_else(|| "private file".to_owned()),
+            self.size.map(format_size).unwrap_or_default(),
+            self.date.map(format_date).unwrap_or_default(),
+            "decode with filegram".to_owned(),
+        ]
+    }
+
+    /// Banner `width` pixels wide and [`ROWS`](Self::ROWS) high, with dark
+    /// text on a light background. Lines that don't fit end in `..`.
+    pub fn render(&self, width: u32) -> RgbImage {
+        let mut banner = RgbImage::from_pixel(width, Self::ROWS, BACKGROUND);
+        let fits = (width.saturating_sub(2 * MARGIN) + 1) / ADVANCE;
+        for (i, line) in self.lines().iter().enumerate() {
+            let mut chars: Vec<char> = line.chars().collect();
+            if chars.len() > fits as usize {
+                chars.truncate(fits.saturating_sub(2) as usize);
+                chars.extend(['.', '.']);
+            }
+            let top = MARGIN + i as u32 * LINE_HEIGHT;
+            for (j, c) in chars.into_iter().enumerate() {
+                draw(&mut banner, glyph(c), MARGIN + j as u32 * ADVANCE, top);
+            }
+        }
+        banner
+    }
+}
+
+fn draw(banner: &mut RgbImage, glyph: [u8; 5], left: u32, top: u32) {
+    for (y, bits) in glyph.into_iter().enumerate() {
+        for x in 0..GLYPH_WIDTH {
+            if bits & (1 << (GLYPH_WIDTH - 1 - x)) != 0 {
+                banner.put_pixel(left + x, top + y as u32, INK);
+            }
+        }
+    }
+}
+
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if size < 1024 {
+        return format!("{size} B");
+    }
+    let mut value = size as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+/// UTC date of `time` as `YYYY-MM-DD`.
+fn format_date(time: SystemTime) -> String {
+    let days = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs() / 86_400) as i64;
+    // civil_from_days from Howard Hinnant's date algorithms
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// Frame rows of the banner of an image `columns` rows wide, after
+/// `header_rows` header rows. It starts on a new pixel row, so the payload
+/// starts at the end of the range; without a banner the range is empty.
+/// `None` when the rows don't fit in the address space.
+pub(crate) fn banner_span(
+    header_rows: usize,
+    banner_rows: u32,
+    columns: usize,
+) -> Option<Range<usize>> {
+    if banner_rows == 0 {
+        return Some(header_rows..header_rows);
+    }
+    let start = header_rows.div_ceil(columns).checked_mul(columns)?;
+    let len = usize::try_from(banner_rows).ok()?.checked_mul(columns)?;
+    Some(start..start.checked_add(len)?)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::IMAGE_WIDTH;
+
+    #[test]
+    fn label_test() {
+        let label = Label {
+            file_name: Some("a-rather-long-file-name.tar.gz".to_owned()),
+            size: Some(3 * 1024 * 1024 / 2),
+            date: Some(UNIX_EPOCH + Duration::from_secs(1_792_368_000)),
+        };
+        assert_eq!(
+            vec![
+                "a-rather-long-file-name.tar.gz",
+                "1.5 MiB",
+                "2026-10-19",
+                "decode with filegram"
+            ],
+            label.lines()
+        );
+        assert_eq!("1970-01-01", format_date(UNIX_EPOCH));
+        assert_eq!(
+            "2000-02-29",
+            format_date(UNIX_EPOCH + Duration::from_secs(951_782_400))
+        );
+        assert_eq!("1023 B", format_size(1023));
+
+        let banner = label.render(IMAGE_WIDTH as u32);
+        assert_eq!((IMAGE_WIDTH as u32, Label::ROWS), banner.dimensions());
+        // the last line just fits, and the margins stay blank
+        let inked = |x: u32| (0..Label::ROWS).any(|y| *banner.get_pixel(x, y) == INK);
+        assert!(inked(MARGIN) && inked(MARGIN + 19 * ADVANCE + 2));
+        assert!(!inked(0) && !inked(IMAGE_WIDTH as u32 - 1));
+        assert_eq!(glyph('?'), glyph('é'));
+        assert_eq!(glyph('A'), glyph('a'));
+
+        assert_eq!(Some(3..3), banner_span(3, 0, 2));
+        assert_eq!(Some(4..64), banner_span(3, 30, 2));
+        assert_eq!(Some(1..31), banner_span(1, 30, 1));
+        assert_eq!(None, banner_span(usize::MAX - 1, 30, 1));
+        assert_eq!(None, banner_span(1, u32::MAX, usize::MAX / 2));
+    }
+}
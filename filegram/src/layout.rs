@@ -0,0 +1,264 @@
+use std::{collections::HashMap, error::Error};
+
+use filegram_core::frame;
+use serde::Deserialize;
+
+use crate::IMAGE_WIDTH;
+
+/// Bytes a PNG file adds around its filtered rows, besides the stored
+/// deflate blocks: signature, IHDR, IEND and the zlib header.
+const PNG_OVERHEAD: u64 = 1024;
+/// Smallest stored deflate block assumed by the size estimate, each adding a
+/// 5 byte header.
+const STORED_BLOCK_SIZE: u64 = 16 * 1024;
+
+/// Limits a host puts on uploaded images. Missing limits don't constrain
+/// the layout.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Limits {
+    pub max_pixels: Option<u64>,
+    /// longest width or height in pixels
+    pub max_side: Option<u32>,
+    /// size of the PNG file
+    pub max_bytes: Option<u64>,
+    /// preferred width divided by height
+    pub aspect_ratio: f64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_pixels: None,
+            max_side: None,
+            max_bytes: None,
+            aspect_ratio: 1.0,
+        }
+    }
+}
+
+impl Limits {
+    fn allows(&self, part: &Part) -> bool {
+        let side = part.width.max(part.height);
+        self.max_side.is_none_or(|max| side <= max)
+            && self.max_pixels.is_none_or(|max| part.pixels() <= max)
+            && self.max_bytes.is_none_or(|max| part.estimated_len <= max)
+    }
+}
+
+/// Named host limits, starting with the built-in ones.
+#[derive(Debug, Clone)]
+pub struct Profiles {
+    profiles: HashMap<String, Limits>,
+}
+
+impl Default for Profiles {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiles {
+    /// Built-in profiles, with the limits their hosts documented when they
+    /// were added:
+    /// - `square`: no limits, only a square shape
+    /// - `discord`: 10 MiB uploads
+    /// - `imgur`: 5 MB, above which PNG files are converted to JPEG
+    pub fn new() -> Self {
+        let profiles = [
+            ("square", Limits::default()),
+            (
+                "discord",
+                Limits {
+                    max_bytes: Some(10 * 1024 * 1024),
+                    ..Limits::default()
+                },
+            ),
+            (
+                "imgur",
+                Limits {
+                    max_bytes: Some(5_000_000),
+                    ..Limits::default()
+                },
+            ),
+        ];
+        Profiles {
+            profiles: profiles
+                .into_iter()
+                .map(|(name, limits)| (name.to_owned(), limits))
+                .collect(),
+        }
+    }
+
+    /// Adds the profiles of a JSON object mapping names to [`Limits`],
+    /// replacing built-in ones of the same name.
+    pub fn load(&mut self, json: &[u8]) -> Result<&mut Self, Box<dyn Error>> {
+        let profiles: HashMap<String, Limits> = serde_json::from_slice(json)?;
+        self.profiles.extend(profiles);
+        Ok(self)
+    }
+
+    pub fn insert(&mut self, name: &str, limits: Limits) -> &mut Self {
+        self.profiles.insert(name.to_owned(), limits);
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Limits> {
+        self.profiles.get(name)
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<_> = self.profiles.keys().map(String::as_str).collect();
+        names.sort();
+        names
+    }
+}
+
+/// One image of a [`Plan`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Part {
+    pub width: u32,
+    pub height: u32,
+    /// payload bytes stored in the image
+    pub payload_len: usize,
+    /// upper bound of the PNG size for payloads that don't compress, e.g.
+    /// encrypted ones
+    pub estimated_len: u64,
+}
+
+impl Part {
+    fn new(header_len: usize, payload_len: usize, columns: usize) -> Self {
+        let height = frame::height(header_len, payload_len).div_ceil(columns);
+        let width = IMAGE_WIDTH * columns;
+        // every PNG row starts with a filter type byte
+        let filtered = (height * (width * 3 + 1)) as u64;
+        Part {
+            width: width as u32,
+            height: height as u32,
+            payload_len,
+            estimated_len: filtered + filtered.div_ceil(STORED_BLOCK_SIZE) * 5 + PNG_OVERHEAD,
+        }
+    }
+
+    pub fn pixels(&self) -> u64 {
+        self.width as u64 * self.height as u64
+    }
+}
+
+/// Dimensions and number of the images holding a payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Plan {
+    /// frame rows side by side in every pixel row
+    pub columns: usize,
+    /// images in order, each holding a slice of the payload and recording
+    /// its index in the header `part` and `parts` fields
+    pub parts: Vec<Part>,
+}
+
+impl Plan {
+    /// Fewest images within `limits` for `payload_len` bytes, each with a
+    /// header of `header_len` bytes, shaped as close to the preferred aspect
+    /// ratio as whole rows allow.
+    pub fn new(
+        payload_len: usize,
+        header_len: usize,
+        limits: &Limits,
+    ) -> Result<Self, Box<dyn Error>> {
+        if !(limits.aspect_ratio.is_finite() && limits.aspect_ratio > 0.0) {
+            Err("Aspect ratio must be positive")?
+        }
+        if Self::columns(header_len, 0, limits).is_none() {
+            Err("Limits are too small for the image header")?
+        }
+        let mut parts = 1;
+        loop {
+            let part_len = payload_len.div_ceil(parts);
+            if let Some(columns) = Self::columns(header_len, part_len, limits) {
+                let parts = (0..parts)
+                    .map(|part| {
+                        let start = (part * part_len).min(payload_len);
+                        let len = part_len.min(payload_len - start);
+                        Part::new(header_len, len, columns)
+                    })
+                    .collect();
+                return Ok(Plan { columns, parts });
+            }
+            parts += 1;
+        }
+    }
+
+    /// Columns for images of `part_len` bytes that fit `limits`, if any.
+    fn columns(header_len: usize, part_len: usize, limits: &Limits) -> Option<usize> {
+        let rows = frame::height(header_len, part_len);
+        let max_side = limits.max_side.map_or(usize::MAX, |max| max as usize);
+        let min_columns = rows.div_ceil(max_side).max(1);
+        let max_columns = rows.min(max_side / IMAGE_WIDTH);
+        if min_columns > max_columns {
+            return None;
+        }
+        // width / height = IMAGE_WIDTH * columns^2 / rows
+        let ideal = (limits.aspect_ratio * rows as f64 / IMAGE_WIDTH as f64).sqrt();
+        let shape_error = |columns: usize| {
+            let part = Part::new(header_len, part_len, columns);
+            (part.width as f64 / part.height as f64 / limits.aspect_ratio)
+                .ln()
+                .abs()
+        };
+        [ideal.floor() as usize, ideal.ceil() as usize]
+            .into_iter()
+            .map(|columns| columns.clamp(min_columns, max_columns))
+            .filter(|&columns| limits.allows(&Part::new(header_len, part_len, columns)))
+            .min_by(|a, b| shape_error(*a).total_cmp(&shape_error(*b)))
+    }
+
+    /// Total payload bytes of all parts.
+    pub fn payload_len(&self) -> usize {
+        self.parts.iter().map(|part| part.payload_len).sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn plan_test() {
+        let square = Plan::new(10_000_000, 100, &Limits::default()).unwrap();
+        assert_eq!(1, square.parts.len());
+        let part = square.parts[0];
+        assert!(part.width.abs_diff(part.height) <= IMAGE_WIDTH as u32);
+        assert_eq!(part.width as usize, square.columns * IMAGE_WIDTH);
+
+        let limits = Limits {
+            max_side: Some(1000),
+            max_bytes: Some(1_000_000),
+            aspect_ratio: 2.0,
+            ..Limits::default()
+        };
+        let plan = Plan::new(10_000_000, 100, &limits).unwrap();
+        assert_eq!(11, plan.parts.len());
+        assert_eq!(10_000_000, plan.payload_len());
+        for part in &plan.parts {
+            assert!(limits.allows(part));
+            assert!(part.width <= 1000 && part.height <= 1000);
+        }
+        let rows = frame::height(100, plan.parts[0].payload_len);
+        assert_eq!(rows.div_ceil(plan.columns), plan.parts[0].height as usize);
+        assert!(plan.parts[0].width > plan.parts[0].height);
+
+        let tiny = Limits {
+            max_side: Some(IMAGE_WIDTH as u32 - 1),
+            ..Limits::default()
+        };
+        assert!(Plan::new(1000, 100, &tiny).is_err());
+        assert!(Plan::new(0, 0, &Limits::default()).is_ok());
+
+        let mut profiles = Profiles::new();
+        profiles
+            .load(br#"{"tall": {"max_side": 4000, "aspect_ratio": 0.5}}"#)
+            .unwrap();
+        assert_eq!(Some(0.5), profiles.get("tall").map(|l| l.aspect_ratio));
+        assert_eq!(None, profiles.get("tall").unwrap().max_bytes);
+        assert_eq!(vec!["discord", "imgur", "square", "tall"], profiles.names());
+    }
+}
@@ -1,8 +1,25 @@
+mod armor;
+pub mod calibration;
+pub mod carrier;
 pub mod decode;
+pub mod deniable;
 pub mod encode;
 pub mod encryption;
-mod padding;
+pub mod envelope;
+pub mod file;
+pub mod header;
+pub mod keyslot;
+pub mod label;
+pub mod layout;
+#[cfg(feature = "mmap")]
+pub mod mmap;
+pub mod padding;
+pub mod pipeline;
+pub mod progress;
+mod tlv;
 mod utils;
 
-const IMAGE_WIDTH: usize = 85;
-const BUFFER_SIZE: usize = 255;
+use filegram_core::{BUFFER_SIZE, IMAGE_WIDTH};
+
+/// Rows filled or read between progress updates and cancellation checks.
+const BATCH_ROWS: usize = 1024;
@@ -0,0 +1,36 @@
+//! Memory-mapped files, so huge inputs reach the encoder and decoded
+//! payloads reach the disk without a copy on the heap.
+//!
+//! A mapping sees changes other processes make to its file, so the files
+//! mustn't be modified while they're mapped.
+
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    path::Path,
+};
+
+pub use memmap2::{Mmap, MmapMut};
+
+/// Maps the whole file at `path` for reading, e.g. as the payload of an
+/// [`Encoder`](crate::encode::Encoder).
+pub fn open(path: impl AsRef<Path>) -> io::Result<Mmap> {
+    let file = File::open(path)?;
+    // SAFETY: the module docs require mapped files not to be modified
+    unsafe { Mmap::map(&file) }
+}
+
+/// Creates or truncates the file at `path` to `len` bytes and maps it for
+/// writing, e.g. as the output of [`payload_into`](crate::decode::payload_into).
+/// The data is written back when the mapping is flushed or dropped.
+pub fn create(path: impl AsRef<Path>, len: usize) -> io::Result<MmapMut> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    file.set_len(len as u64)?;
+    // SAFETY: the module docs require mapped files not to be modified
+    unsafe { MmapMut::map_mut(&file) }
+}
@@ -1,22 +1,81 @@
-use block_padding::{
-    generic_array::{typenum::U255, GenericArray},
-    AnsiX923, Padding, UnpadError,
-};
-
-use crate::BUFFER_SIZE;
-
-pub fn pad_block(data: Vec<u8>) -> Vec<u8> {
-    let mut block: GenericArray<u8, U255> = GenericArray::clone_from_slice(&[0u8; BUFFER_SIZE]);
-    let data_len = data.len();
-    block[..data_len].copy_from_slice(&data);
-    AnsiX923::pad(&mut block, data_len);
-    block.to_vec()
+use std::{error::Error, str::FromStr};
+
+const KIB: usize = 1024;
+const MIB: usize = 1024 * KIB;
+
+/// Size classes that padded payloads are rounded up to, so that image
+/// dimensions don't reveal the exact size of an encrypted file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bucket {
+    /// next power of two
+    PowerOfTwo,
+    /// next multiple of the given number of bytes
+    Fixed(usize),
+}
+
+impl Bucket {
+    /// Size of the bucket holding `len` bytes, failing when it doesn't fit
+    /// in the address space.
+    pub fn padded_len(&self, len: usize) -> Result<usize, Box<dyn Error>> {
+        let padded = match self {
+            Bucket::PowerOfTwo => len.checked_next_power_of_two(),
+            Bucket::Fixed(step) => len.div_ceil(*step).max(1).checked_mul(*step),
+        };
+        Ok(padded.ok_or("Payload too large for its padding bucket")?)
+    }
+
+    /// Extends `data` with zeros up to the bucket size.
+    pub fn pad(&self, data: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
+        data.resize(self.padded_len(data.len())?, 0);
+        Ok(())
+    }
 }
 
-pub fn unpad_block(data: &[u8]) -> Result<Vec<u8>, UnpadError> {
-    let mut block: GenericArray<u8, U255> = GenericArray::clone_from_slice(&[0u8; BUFFER_SIZE]);
-    let data_len = data.len();
-    block[..data_len].copy_from_slice(data);
-    let data = AnsiX923::unpad(&block)?;
-    Ok(data.to_vec())
+impl FromStr for Bucket {
+    type Err = String;
+
+    /// Accepts `pow2` or a step size in bytes with an optional `kib`/`mib` suffix.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_lowercase();
+        if s == "pow2" {
+            return Ok(Bucket::PowerOfTwo);
+        }
+        let (number, unit) = if let Some(number) = s.strip_suffix("mib") {
+            (number, MIB)
+        } else if let Some(number) = s.strip_suffix("kib") {
+            (number, KIB)
+        } else {
+            (s.as_str(), 1)
+        };
+        match number.trim().parse::<usize>() {
+            Ok(n) if n > 0 => n
+                .checked_mul(unit)
+                .map(Bucket::Fixed)
+                .ok_or_else(|| format!("Bucket size '{s}' is too large")),
+            _ => Err(format!("Invalid bucket size '{s}'")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bucket_test() {
+        assert_eq!(Ok(Bucket::PowerOfTwo), "pow2".parse());
+        assert_eq!(Ok(Bucket::Fixed(4 * MIB)), "4MiB".parse());
+        assert_eq!(Ok(Bucket::Fixed(512 * KIB)), "512kib".parse());
+        assert!("0".parse::<Bucket>().is_err());
+
+        assert_eq!(1024, Bucket::PowerOfTwo.padded_len(1000).unwrap());
+        assert_eq!(MIB, Bucket::Fixed(MIB).padded_len(1).unwrap());
+        assert_eq!(MIB, Bucket::Fixed(MIB).padded_len(0).unwrap());
+        assert_eq!(2 * MIB, Bucket::Fixed(MIB).padded_len(MIB + 1).unwrap());
+
+        let huge = Bucket::Fixed(usize::MAX - 1);
+        assert_eq!(usize::MAX - 1, huge.padded_len(1).unwrap());
+        assert!(huge.padded_len(usize::MAX).is_err());
+        assert!(Bucket::PowerOfTwo.padded_len(usize::MAX / 2 + 2).is_err());
+    }
 }
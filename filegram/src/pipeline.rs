@@ -0,0 +1,515 @@
+use std::{collections::HashMap, error::Error};
+
+use crate::{
+    encryption::{Cipher, Key, TAG_SIZE},
+    header::Header,
+    padding::Bucket,
+    tlv, BUFFER_SIZE,
+};
+
+const TAG_NAME: u8 = 1;
+const TAG_PARAMS: u8 = 2;
+
+const DEFAULT_COMPRESSION_LEVEL: u8 = 6;
+
+/// Most a compressed payload may inflate to, whatever its stage records.
+pub const MAX_INFLATED_SIZE: usize = 1 << 30;
+
+/// Name and parameters of a transform, recorded in the image header so the
+/// decoder can rebuild it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stage {
+    pub name: String,
+    pub params: Vec<u8>,
+}
+
+impl Stage {
+    pub fn new(name: &str) -> Self {
+        Stage {
+            name: name.to_owned(),
+            params: Vec::new(),
+        }
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut fields = tlv::Writer::new();
+        fields.field(TAG_NAME, self.name.as_bytes());
+        if !self.params.is_empty() {
+            fields.field(TAG_PARAMS, &self.params);
+        }
+        fields.into_bytes()
+    }
+
+    pub(crate) fn from_bytes(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let mut stage = Stage::new("");
+        let mut fields = tlv::Reader::new(data);
+        while let Some((tag, value)) = fields.next_field()? {
+            match tag {
+                TAG_NAME => stage.name = String::from_utf8(value.to_vec())?,
+                TAG_PARAMS => stage.params = value.to_vec(),
+                _ => Err(format!("Unsupported stage field {tag}"))?,
+            }
+        }
+        if stage.name.is_empty() {
+            Err("Stage without a name")?
+        }
+        Ok(stage)
+    }
+}
+
+/// Reversible step applied to the payload before it is written to pixels.
+///
+/// `aad` is the [associated data](Header::associated_data) of the image
+/// header, for stages that authenticate the payload together with it.
+pub trait Transform {
+    fn stage(&self) -> Stage;
+    fn forward(&self, data: Vec<u8>, aad: &[u8]) -> Result<Vec<u8>, Box<dyn Error>>;
+    fn inverse(&self, data: Vec<u8>, aad: &[u8]) -> Result<Vec<u8>, Box<dyn Error>>;
+
+    /// Most bytes [`forward`](Self::forward) turns `len` bytes into, for
+    /// sizing an image without running the stage, if the stage knows it.
+    fn max_forward_len(&self, _len: usize) -> Option<usize> {
+        None
+    }
+}
+
+/// DEFLATE compression. The decoder won't inflate the payload past its
+/// limit, so a small image can't expand to fill the memory.
+pub struct Compress {
+    level: u8,
+    limit: usize,
+}
+
+impl Compress {
+    pub const NAME: &'static str = "deflate";
+
+    /// `level` goes from 0, no compression, to 10, the slowest.
+    pub fn new(level: u8) -> Self {
+        Compress {
+            level,
+            limit: MAX_INFLATED_SIZE,
+        }
+    }
+
+    /// Records `limit` as the most the payload inflates to, rather than
+    /// [`MAX_INFLATED_SIZE`]. The limit is visible in the header.
+    pub fn with_limit(mut self, limit: usize) -> Result<Self, Box<dyn Error>> {
+        if limit > MAX_INFLATED_SIZE {
+            Err("Payload too large to compress")?
+        }
+        self.limit = limit;
+        Ok(self)
+    }
+
+    /// Empty for the default limit, as images from before limits were
+    /// recorded have it.
+    fn params(&self) -> Vec<u8> {
+        if self.limit == MAX_INFLATED_SIZE {
+            return Vec::new();
+        }
+        (self.limit as u64).to_be_bytes().to_vec()
+    }
+
+    fn from_params(params: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let compress = Self::default();
+        if params.is_empty() {
+            return Ok(compress);
+        }
+        let limit = usize::try_from(tlv::read_u64(params)?).unwrap_or(usize::MAX);
+        compress.with_limit(limit)
+    }
+}
+
+impl Default for Compress {
+    fn default() -> Self {
+        Self::new(DEFAULT_COMPRESSION_LEVEL)
+    }
+}
+
+impl Transform for Compress {
+    fn stage(&self) -> Stage {
+        Stage {
+            params: self.params(),
+            ..Stage::new(Self::NAME)
+        }
+    }
+
+    fn forward(&self, data: Vec<u8>, _aad: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        if data.len() > self.limit {
+            Err("Payload too large to compress")?
+        }
+        Ok(miniz_oxide::deflate::compress_to_vec(&data, self.level))
+    }
+
+    /// The bound of zlib's `deflateBound`, without the zlib wrapper.
+    fn max_forward_len(&self, len: usize) -> Option<usize> {
+        len.checked_add((len >> 12) + (len >> 14) + (len >> 25) + 7)
+    }
+
+    fn inverse(&self, data: Vec<u8>, _aad: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        miniz_oxide::inflate::decompress_to_vec_with_limit(&data, self.limit)
+            .map_err(|e| format!("Couldn't decompress payload: {e}").into())
+    }
+}
+
+/// Pads the payload up to a [`Bucket`] size, after a length prefix. It
+/// belongs after compression, which would squeeze the padding out again.
+pub struct Pad {
+    bucket: Bucket,
+}
+
+impl Pad {
+    pub const NAME: &'static str = "pad";
+
+    pub fn new(bucket: Bucket) -> Self {
+        Pad { bucket }
+    }
+
+    /// 0 for powers of two, otherwise the step size.
+    fn params(&self) -> Vec<u8> {
+        let step = match self.bucket {
+            Bucket::PowerOfTwo => 0,
+            Bucket::Fixed(step) => step as u64,
+        };
+        step.to_be_bytes().to_vec()
+    }
+
+    fn from_params(params: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let bucket = match tlv::read_u64(params)? {
+            0 => Bucket::PowerOfTwo,
+            step => Bucket::Fixed(usize::try_from(step)?),
+        };
+        Ok(Self::new(bucket))
+    }
+}
+
+impl Transform for Pad {
+    fn stage(&self) -> Stage {
+        Stage {
+            params: self.params(),
+            ..Stage::new(Self::NAME)
+        }
+    }
+
+    fn forward(&self, data: Vec<u8>, _aad: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut padded = Vec::with_capacity(self.bucket.padded_len(data.len() + 8)?);
+        padded.extend_from_slice(&(data.len() as u64).to_be_bytes());
+        padded.extend_from_slice(&data);
+        self.bucket.pad(&mut padded)?;
+        Ok(padded)
+    }
+
+    fn max_forward_len(&self, len: usize) -> Option<usize> {
+        self.bucket.padded_len(len.checked_add(8)?).ok()
+    }
+
+    fn inverse(&self, mut data: Vec<u8>, _aad: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        if data.len() < 8 {
+            Err("Truncated padding")?
+        }
+        let len = usize::try_from(tlv::read_u64(&data[..8])?)?;
+        if data.len() - 8 < len {
+            Err("Truncated padding")?
+        }
+        data.truncate(8 + len);
+        data.drain(..8);
+        Ok(data)
+    }
+}
+
+/// Stores every row as running sums along its red, green and blue planes,
+/// so the PNG Sub filter, which takes each byte minus the one a pixel to its
+/// left, gives back the payload bytes as they are. Adaptive filtering picks
+/// Sub for such rows and deflate finds the repeated strings of text, which
+/// the filtered RGB triples hide. It doesn't help at the fast compression,
+/// which doesn't look for repeated strings, nor compressed or encrypted
+/// payloads. It belongs last, as the rows it rearranges are those written to
+/// pixels.
+pub struct Planar;
+
+impl Planar {
+    pub const NAME: &'static str = "planar";
+
+    /// Subtracted from each byte before summing, so lowercase text comes out
+    /// of the Sub filter near zero, which adaptive filtering prefers.
+    const OFFSET: u8 = b'`';
+}
+
+impl Transform for Planar {
+    fn stage(&self) -> Stage {
+        Stage::new(Self::NAME)
+    }
+
+    fn forward(&self, mut data: Vec<u8>, _aad: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        // rows start at zero like PNG rows, as the payload starts on one
+        for row in data.chunks_mut(BUFFER_SIZE) {
+            for i in 0..row.len() {
+                let left = i.checked_sub(3).map_or(0, |left| row[left]);
+                row[i] = left.wrapping_add(row[i].wrapping_sub(Self::OFFSET));
+            }
+        }
+        Ok(data)
+    }
+
+    fn inverse(&self, mut data: Vec<u8>, _aad: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        for row in data.chunks_mut(BUFFER_SIZE) {
+            for i in (0..row.len()).rev() {
+                let left = i.checked_sub(3).map_or(0, |left| row[left]);
+                row[i] = row[i].wrapping_sub(left).wrapping_add(Self::OFFSET);
+            }
+        }
+        Ok(data)
+    }
+
+    fn max_forward_len(&self, len: usize) -> Option<usize> {
+        Some(len)
+    }
+}
+
+/// ChaCha20-Poly1305 encryption, bound to the image header.
+pub struct Encrypt {
+    cipher: Cipher,
+}
+
+impl Encrypt {
+    pub const NAME: &'static str = "chacha20poly1305";
+
+    pub fn new(cipher: Cipher) -> Self {
+        Encrypt { cipher }
+    }
+}
+
+impl Transform for Encrypt {
+    fn stage(&self) -> Stage {
+        Stage::new(Self::NAME)
+    }
+
+    fn forward(&self, data: Vec<u8>, aad: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(self.cipher.encrypt(&data, aad))
+    }
+
+    fn inverse(&self, data: Vec<u8>, aad: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.cipher.decrypt(&data, aad)
+    }
+
+    fn max_forward_len(&self, len: usize) -> Option<usize> {
+        len.checked_add(TAG_SIZE)
+    }
+}
+
+/// Builds a transform from its recorded parameters.
+pub type Factory = Box<dyn Fn(&[u8]) -> Result<Box<dyn Transform>, Box<dyn Error>>>;
+
+/// Transforms the decoder knows how to rebuild, by stage name.
+pub struct Registry {
+    factories: HashMap<String, Factory>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Registry {
+    /// Registry with the built-in stages that need no secrets.
+    pub fn new() -> Self {
+        let mut registry = Registry {
+            factories: HashMap::new(),
+        };
+        registry.register(Compress::NAME, |params| {
+            Ok(Box::new(Compress::from_params(params)?))
+        });
+        registry.register(Pad::NAME, |params| Ok(Box::new(Pad::from_params(params)?)));
+        registry.register(Planar::NAME, |_| Ok(Box::new(Planar)));
+        registry
+    }
+
+    pub fn register<F>(&mut self, name: &str, factory: F) -> &mut Self
+    where
+        F: Fn(&[u8]) -> Result<Box<dyn Transform>, Box<dyn Error>> + 'static,
+    {
+        self.factories.insert(name.to_owned(), Box::new(factory));
+        self
+    }
+
+    /// Registers the encryption stage with the image's data key.
+    pub fn with_key(&mut self, key: Key) -> &mut Self {
+        self.register(Encrypt::NAME, move |_| {
+            Ok(Box::new(Encrypt::new(Cipher::load(&key))))
+        })
+    }
+
+    fn build(&self, stage: &Stage) -> Result<Box<dyn Transform>, Box<dyn Error>> {
+        match self.factories.get(&stage.name) {
+            Some(factory) => factory(&stage.params),
+            None if stage.name == Encrypt::NAME => Err("Image is encrypted, a key is required")?,
+            None => Err(format!("Unsupported stage '{}'", stage.name))?,
+        }
+    }
+}
+
+/// Chain of transforms run in order on encode and in reverse on decode.
+#[derive(Default)]
+pub struct Pipeline {
+    stages: Vec<Box<dyn Transform>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, stage: impl Transform + 'static) -> Self {
+        self.stages.push(Box::new(stage));
+        self
+    }
+
+    /// Rebuilds the pipeline recorded in `header`. Encrypted images from
+    /// before stages were recorded have a single encryption stage.
+    pub fn from_header(header: &Header, registry: &Registry) -> Result<Self, Box<dyn Error>> {
+        let legacy = [Stage::new(Encrypt::NAME)];
+        let stages = match &header.stages[..] {
+            [] if header.encrypted => &legacy[..],
+            stages => stages,
+        };
+        let stages = stages
+            .iter()
+            .map(|stage| registry.build(stage))
+            .collect::<Result<_, _>>()?;
+        Ok(Pipeline { stages })
+    }
+
+    /// Stages to record in the image header.
+    pub fn stages(&self) -> Vec<Stage> {
+        self.stages.iter().map(|stage| stage.stage()).collect()
+    }
+
+    pub fn forward(&self, data: Vec<u8>, aad: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.stages
+            .iter()
+            .try_fold(data, |data, stage| stage.forward(data, aad))
+    }
+
+    pub fn inverse(&self, data: Vec<u8>, aad: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.stages
+            .iter()
+            .rev()
+            .try_fold(data, |data, stage| stage.inverse(data, aad))
+    }
+
+    /// Most bytes [`forward`](Self::forward) turns `len` bytes into, if
+    /// every stage knows it.
+    pub fn max_forward_len(&self, len: usize) -> Option<usize> {
+        self.stages
+            .iter()
+            .try_fold(len, |len, stage| stage.max_forward_len(len))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Third party stage flipping every bit.
+    struct Invert;
+
+    impl Transform for Invert {
+        fn stage(&self) -> Stage {
+            Stage::new("invert")
+        }
+
+        fn forward(&self, data: Vec<u8>, _aad: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+            Ok(data.into_iter().map(|b| !b).collect())
+        }
+
+        fn inverse(&self, data: Vec<u8>, aad: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+            self.forward(data, aad)
+        }
+    }
+
+    #[test]
+    fn pipeline_test() {
+        let cipher = Cipher::new();
+        let key = Key::from_bytes(&cipher.key().to_bytes()).unwrap();
+        let pipeline = Pipeline::new()
+            .with(Invert)
+            .with(Compress::default())
+            .with(Pad::new(Bucket::Fixed(1000)))
+            .with(Encrypt::new(cipher))
+            .with(Planar);
+        let mut header = Header::new();
+        header.encrypted = true;
+        header.stages = pipeline.stages();
+        let aad = header.associated_data();
+        let data = b"filegram ".repeat(100);
+        let payload = pipeline.forward(data.clone(), &aad).unwrap();
+        assert_eq!(1000 + 16, payload.len());
+
+        let bytes = header.to_bytes();
+        let (header, _) = Header::from_bytes(&bytes).unwrap();
+        let mut registry = Registry::new();
+        assert!(Pipeline::from_header(&header, &registry).is_err());
+        registry.register("invert", |_| Ok(Box::new(Invert)));
+        assert!(Pipeline::from_header(&header, &registry).is_err());
+        registry.with_key(key);
+        let pipeline = Pipeline::from_header(&header, &registry).unwrap();
+
+        assert_eq!(data, pipeline.inverse(payload, &aad).unwrap());
+    }
+
+    #[test]
+    fn max_forward_len_test() {
+        // noise, which deflate can only store
+        let mut state = 1u32;
+        let noise: Vec<u8> = (0..300_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        let text = b"filegram ".repeat(1000);
+        let pipeline = Pipeline::new()
+            .with(Compress::default())
+            .with(Pad::new(Bucket::Fixed(1000)))
+            .with(Encrypt::new(Cipher::new()))
+            .with(Planar);
+        for data in [&noise[..], &noise[..1], &[], &text] {
+            let bound = pipeline.max_forward_len(data.len()).unwrap();
+            let len = pipeline.forward(data.to_vec(), &[]).unwrap().len();
+            assert!(len <= bound);
+        }
+        // noise stays close to its bound, as the rest of the stages are exact
+        let len = pipeline.forward(noise.clone(), &[]).unwrap().len();
+        assert!(pipeline.max_forward_len(noise.len()).unwrap() < len + 1000);
+        assert_eq!(None, Pipeline::new().with(Invert).max_forward_len(0));
+    }
+
+    #[test]
+    fn compress_limit_test() {
+        let data = vec![0; 10_000];
+        let compress = Compress::default().with_limit(data.len()).unwrap();
+        let stage = Stage::from_bytes(&compress.stage().to_bytes()).unwrap();
+        let payload = compress.forward(data.clone(), &[]).unwrap();
+        let registry = Registry::new();
+        let exact = registry.build(&stage).unwrap();
+        assert_eq!(data, exact.inverse(payload.clone(), &[]).unwrap());
+
+        let small = Compress::default().with_limit(data.len() - 1).unwrap();
+        assert!(small.forward(data.clone(), &[]).is_err());
+        let stage = Stage::from_bytes(&small.stage().to_bytes()).unwrap();
+        let small = registry.build(&stage).unwrap();
+        assert!(small.inverse(payload, &[]).is_err());
+
+        let huge = Stage {
+            params: u64::MAX.to_be_bytes().to_vec(),
+            ..Stage::new(Compress::NAME)
+        };
+        assert!(registry.build(&huge).is_err());
+        assert!(Compress::default()
+            .with_limit(MAX_INFLATED_SIZE + 1)
+            .is_err());
+    }
+}
@@ -0,0 +1,90 @@
+use std::{
+    error::Error,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// How far an encode or decode got. Rows count every image row, header rows
+/// included. Bytes count the payload when encoding and the whole image when
+/// decoding, as the payload size isn't known before the header is read.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Status {
+    pub rows_done: usize,
+    pub rows_total: usize,
+    pub bytes_done: usize,
+    pub bytes_total: usize,
+}
+
+/// Receives a [`Status`] after every batch of rows. Implemented for closures
+/// taking a `Status`.
+pub trait Progress {
+    fn update(&mut self, status: Status);
+}
+
+impl<F: FnMut(Status)> Progress for F {
+    fn update(&mut self, status: Status) {
+        self(status)
+    }
+}
+
+/// Token stopping an encode or decode between batches of rows. Clones share
+/// the same flag, so one can be handed to the work and another kept to cancel
+/// it.
+#[derive(Debug, Default, Clone)]
+pub struct Cancel(Arc<AtomicBool>);
+
+impl Cancel {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn check(&self) -> Result<(), Cancelled> {
+        match self.is_cancelled() {
+            true => Err(Cancelled),
+            false => Ok(()),
+        }
+    }
+}
+
+/// Error returned by work stopped through a [`Cancel`] token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Cancelled")
+    }
+}
+
+impl Error for Cancelled {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cancel_test() {
+        let cancel = Cancel::new();
+        let handle = cancel.clone();
+        assert_eq!(Ok(()), cancel.check());
+        handle.cancel();
+        assert!(cancel.is_cancelled());
+        assert_eq!(Err(Cancelled), cancel.check());
+
+        let mut statuses = Vec::new();
+        let mut progress = |status| statuses.push(status);
+        progress.update(Status::default());
+        assert_eq!(vec![Status::default()], statuses);
+    }
+}
@@ -0,0 +1,69 @@
+use std::error::Error;
+
+/// Writer for the `tag: u8, length: u32 BE, value` records used by headers
+/// and envelopes.
+#[derive(Default)]
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn field(&mut self, tag: u8, value: &[u8]) -> &mut Self {
+        self.buf.push(tag);
+        self.buf
+            .extend_from_slice(&(value.len() as u32).to_be_bytes());
+        self.buf.extend_from_slice(value);
+        self
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Tag and value of a single record.
+pub type Field<'a> = (u8, &'a [u8]);
+
+pub struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Reader { buf }
+    }
+
+    pub fn next_field(&mut self) -> Result<Option<Field<'a>>, Box<dyn Error>> {
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        if self.buf.len() < 5 {
+            Err("Truncated field")?
+        }
+        let tag = self.buf[0];
+        let len = u32::from_be_bytes(self.buf[1..5].try_into().unwrap()) as usize;
+        let rest = &self.buf[5..];
+        if rest.len() < len {
+            Err("Truncated field")?
+        }
+        let (value, rest) = rest.split_at(len);
+        self.buf = rest;
+        Ok(Some((tag, value)))
+    }
+}
+
+pub fn read_u32(value: &[u8]) -> Result<u32, Box<dyn Error>> {
+    Ok(u32::from_be_bytes(
+        value.try_into().map_err(|_| "Invalid field length")?,
+    ))
+}
+
+pub fn read_u64(value: &[u8]) -> Result<u64, Box<dyn Error>> {
+    Ok(u64::from_be_bytes(
+        value.try_into().map_err(|_| "Invalid field length")?,
+    ))
+}
@@ -1,4 +1,13 @@
 use std::io::{self, Read};
+#[cfg(feature = "async")]
+use std::{
+    io::{BufRead, Seek, SeekFrom, Write},
+    mem,
+    sync::{Arc, Mutex},
+};
+
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 pub fn read_exact(file: &mut impl Read, mut buffer: &mut [u8]) -> io::Result<usize> {
     let mut sum = 0;
@@ -12,3 +21,122 @@ pub fn read_exact(file: &mut impl Read, mut buffer: &mut [u8]) -> io::Result<usi
     }
     Ok(sum)
 }
+
+/// Bytes read from an async input at a time.
+#[cfg(feature = "async")]
+const READ_SIZE: usize = 64 * 1024;
+
+/// Async [`read_exact`].
+#[cfg(feature = "async")]
+pub async fn read_exact_async(
+    file: &mut (impl AsyncRead + Unpin),
+    mut buffer: &mut [u8],
+) -> io::Result<usize> {
+    let mut sum = 0;
+    while !buffer.is_empty() {
+        let n = file.read(buffer).await?;
+        if n == 0 {
+            break;
+        }
+        buffer = &mut buffer[n..];
+        sum += n;
+    }
+    Ok(sum)
+}
+
+/// Buffer shared between the synchronous PNG codec and the async reader or
+/// writer on the other side.
+#[cfg(feature = "async")]
+#[derive(Clone, Default)]
+pub struct Shared(Arc<Mutex<Vec<u8>>>);
+
+#[cfg(feature = "async")]
+impl Shared {
+    pub fn take(&self) -> Vec<u8> {
+        mem::take(&mut self.0.lock().expect("shared buffer lock"))
+    }
+
+    /// Moves what the PNG encoder wrote so far to `output`.
+    pub async fn drain_to(&self, output: &mut (impl AsyncWrite + Unpin)) -> io::Result<()> {
+        output.write_all(&self.take()).await
+    }
+
+    /// Reads more of `input` into the buffer, and tells whether there was
+    /// any left.
+    pub async fn fill_from(&self, input: &mut (impl AsyncRead + Unpin)) -> io::Result<bool> {
+        let mut bytes = vec![0u8; READ_SIZE];
+        let n = input.read(&mut bytes).await?;
+        self.0
+            .lock()
+            .expect("shared buffer lock")
+            .extend_from_slice(&bytes[..n]);
+        Ok(n > 0)
+    }
+
+    /// Reader for the PNG decoder, starting with `start`.
+    pub fn reader(&self, start: Vec<u8>) -> SharedReader {
+        SharedReader {
+            shared: self.clone(),
+            bytes: start,
+            pos: 0,
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .lock()
+            .expect("shared buffer lock")
+            .extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads whatever has reached a [`Shared`] buffer. Running out of bytes
+/// shows as an end of file, after which the PNG decoder can be retried once
+/// more have arrived.
+#[cfg(feature = "async")]
+pub struct SharedReader {
+    shared: Shared,
+    bytes: Vec<u8>,
+    pos: usize,
+}
+
+#[cfg(feature = "async")]
+impl Read for SharedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+#[cfg(feature = "async")]
+impl BufRead for SharedReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.bytes.len() {
+            self.bytes = self.shared.take();
+            self.pos = 0;
+        }
+        Ok(&self.bytes[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt;
+    }
+}
+
+#[cfg(feature = "async")]
+impl Seek for SharedReader {
+    fn seek(&mut self, _pos: SeekFrom) -> io::Result<u64> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
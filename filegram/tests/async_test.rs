@@ -0,0 +1,78 @@
+#![cfg(feature = "async")]
+
+use std::io::Cursor;
+
+use filegram::{
+    decode::{self, decode_async},
+    encode::{self, encode_async},
+    header::Header,
+    label::Label,
+};
+use image::ImageFormat;
+use tokio::io::{self, AsyncWriteExt};
+
+/// The futures have to be `Send` to run on a multi-threaded runtime.
+fn assert_send(_: &impl Send) {}
+
+#[tokio::test]
+async fn encode_decode_async_test() {
+    let header = Header {
+        file_name: Some("test.txt".to_owned()),
+        ..Header::new()
+    };
+    let payload = b"filegram ".repeat(10_000);
+
+    let mut png = Vec::new();
+    let encode = encode_async(&header, &payload[..], payload.len(), &mut png);
+    assert_send(&encode);
+    encode.await.unwrap();
+    let image = decode::image_from_file(Cursor::new(&png)).unwrap();
+    assert_eq!(
+        (Some(header.clone()), payload.clone()),
+        decode::split_header(&image).unwrap()
+    );
+
+    // a small pipe makes the decoder wait for more input mid row
+    let (mut sender, receiver) = io::duplex(64);
+    let send = async {
+        sender.write_all(&png).await.unwrap();
+        sender.shutdown().await.unwrap();
+    };
+    let mut decoded = Vec::new();
+    let decode = decode_async(receiver, &mut decoded);
+    assert_send(&decode);
+    let (_, decoded_header) = tokio::join!(send, decode);
+    assert_eq!(Some(header.clone()), decoded_header.unwrap());
+    assert_eq!(payload, decoded);
+
+    let mut png = Vec::new();
+    assert!(
+        encode_async(&header, &payload[1..], payload.len(), &mut png)
+            .await
+            .is_err()
+    );
+
+    // the rows filling out the last pixel row of a wide image are skipped
+    let image = encode::Encoder::with_columns(&header, &payload[..], 7).into_image();
+    let mut png = Cursor::new(Vec::new());
+    image.write_to(&mut png, ImageFormat::Png).unwrap();
+    let mut decoded = Vec::new();
+    let decoded_header = decode_async(&png.get_ref()[..], &mut decoded).await;
+    assert_eq!(Some(header.clone()), decoded_header.unwrap());
+    assert_eq!(payload, decoded);
+
+    // and so are the banner rows the header records
+    let labelled = Header {
+        banner_rows: Label::ROWS,
+        ..header
+    };
+    let image = encode::Encoder::with_columns(&labelled, &payload[..], 7)
+        .label(&Label::default())
+        .into_image();
+    let mut png = Cursor::new(Vec::new());
+    image.write_to(&mut png, ImageFormat::Png).unwrap();
+    let mut decoded = Vec::new();
+    let decoded_header = decode_async(&png.get_ref()[..], &mut decoded).await;
+    assert_eq!(Some(labelled), decoded_header.unwrap());
+    assert_eq!(payload, decoded);
+}
@@ -1,7 +1,20 @@
-use filegram::{decode, encode};
+use filegram::{
+    calibration,
+    carrier::Carrier,
+    decode,
+    encode::{self, CompressionType, FilterType, PngOptions},
+    encryption::{Cipher, Key},
+    header::Header,
+    keyslot::{self, KeySlot, Secret},
+    label::Label,
+    pipeline::{Pipeline, Planar, Registry},
+    progress::{Cancel, Cancelled, Status},
+};
+use image::{imageops, DynamicImage, ImageFormat, Rgba, RgbaImage};
 use std::{
-    fs::File,
-    io::{BufReader, Read},
+    borrow::Cow,
+    fs::{self, File},
+    io::{BufReader, Cursor, Read},
 };
 
 #[test]
@@ -17,8 +30,356 @@ fn encode_decode_test() {
     let file = File::open(file_path).unwrap();
     let file_size = file.metadata().unwrap().len() as usize;
     let mut file = BufReader::new(file);
-    let rgb = encode::from_reader(&mut file, file_size);
+    let rgb = encode::from_reader(&mut file, file_size).unwrap();
     let data = decode::from_rgb(&rgb).unwrap();
 
     assert_eq!(original_data, data)
 }
+
+#[test]
+fn header_tampering_test() {
+    let header = Header {
+        encrypted: true,
+        file_name: Some("test.txt".to_owned()),
+        ..Header::new()
+    };
+    let cipher = Cipher::new();
+    let data = cipher.encrypt(b"filegram", &header.associated_data());
+    let rgb = encode::with_header(&header, &data);
+
+    let (decoded_header, payload) = decode::split_header(&rgb).unwrap();
+    let aad = decoded_header.unwrap().associated_data();
+    assert_eq!(
+        b"filegram".to_vec(),
+        cipher.decrypt(&payload, &aad).unwrap()
+    );
+
+    for bit in 0..header.to_bytes().len() * 8 {
+        let mut tampered = rgb.clone();
+        let byte = bit / 8;
+        tampered.as_mut()[byte] ^= 1 << (bit % 8);
+
+        let detected = match decode::split_header(&tampered) {
+            Ok((Some(header), payload)) => {
+                cipher.decrypt(&payload, &header.associated_data()).is_err()
+            }
+            _ => true,
+        };
+        assert!(detected, "undetected change of header bit {bit}");
+    }
+}
+
+#[test]
+fn replace_header_test() {
+    let header = Header {
+        file_name: Some("test.txt".to_owned()),
+        ..Header::new()
+    };
+    let payload = vec![7u8; 1000];
+    let rgb = encode::with_header(&header, &payload);
+
+    let long_name = Header {
+        file_name: Some("a".repeat(600)),
+        ..Header::new()
+    };
+    let replaced = encode::replace_header(&rgb, &long_name).unwrap();
+
+    assert_eq!(Some(long_name), decode::read_header(&replaced).unwrap());
+    assert_eq!(payload, decode::split_header(&replaced).unwrap().1);
+}
+
+#[test]
+fn columns_test() {
+    let header = Header {
+        file_name: Some("test.txt".to_owned()),
+        ..Header::new()
+    };
+    let payload = b"filegram ".repeat(1000);
+    let rgb = encode::Encoder::with_columns(&header, &payload[..], 4).into_image();
+    assert_eq!(4 * 85, rgb.width());
+
+    assert_eq!(
+        (Some(header), payload.clone()),
+        decode::split_header(&rgb).unwrap()
+    );
+    let long_name = Header {
+        file_name: Some("a".repeat(600)),
+        ..Header::new()
+    };
+    let replaced = encode::replace_header(&rgb, &long_name).unwrap();
+    assert_eq!(
+        (Some(long_name), payload),
+        decode::split_header(&replaced).unwrap()
+    );
+}
+
+#[test]
+fn banner_test() {
+    let header = Header {
+        file_name: Some("test.txt".to_owned()),
+        banner_rows: Label::ROWS,
+        ..Header::new()
+    };
+    let label = Label {
+        file_name: header.file_name.clone(),
+        size: Some(9000),
+        date: None,
+    };
+    let payload = b"filegram ".repeat(1000);
+    for columns in [1, 3] {
+        let rgb = encode::Encoder::with_columns(&header, &payload[..], columns)
+            .label(&label)
+            .into_image();
+        // the banner starts on the pixel row after the header
+        let banner = label.render(rgb.width());
+        let start = 255 * columns;
+        let end = start + banner.as_raw().len();
+        assert_eq!(banner.as_raw()[..], rgb.as_raw()[start..end]);
+        assert_eq!(
+            (Some(header.clone()), payload.clone()),
+            decode::split_header(&rgb).unwrap()
+        );
+        assert_eq!(payload.len(), decode::payload_len(&rgb).unwrap());
+
+        // a longer header moves the banner down with it
+        let long_name = Header {
+            file_name: Some("a".repeat(600)),
+            ..header.clone()
+        };
+        let replaced = encode::replace_header(&rgb, &long_name).unwrap();
+        let start = 3_usize.div_ceil(columns) * columns * 255;
+        assert_eq!(
+            banner.as_raw()[..],
+            replaced.as_raw()[start..start + banner.as_raw().len()]
+        );
+        assert_eq!(
+            (Some(long_name), payload.clone()),
+            decode::split_header(&replaced).unwrap()
+        );
+        let unlabelled = Header {
+            banner_rows: 0,
+            ..header.clone()
+        };
+        assert!(encode::replace_header(&rgb, &unlabelled).is_err());
+    }
+}
+
+#[test]
+fn png_options_test() {
+    let png_len = |data: &[u8], pipeline: Pipeline, options: &PngOptions| {
+        let header = Header {
+            stages: pipeline.stages(),
+            ..Header::new()
+        };
+        let payload = pipeline.forward(data.to_vec(), &[]).unwrap();
+        let rgb = encode::with_header(&header, &payload);
+        let mut png = Vec::new();
+        encode::write_png(&rgb, &mut png, options).unwrap();
+
+        let image = decode::image_from_file(Cursor::new(&png)).unwrap();
+        let (header, payload) = decode::split_header(&image).unwrap();
+        let pipeline = Pipeline::from_header(&header.unwrap(), &Registry::new()).unwrap();
+        assert_eq!(data, pipeline.inverse(payload, &[]).unwrap());
+        png.len()
+    };
+
+    // text is stored as it is, which deflate does best on without filters
+    let text = fs::read("tests/data/test.txt").unwrap();
+    let tuned = PngOptions {
+        compression: CompressionType::Default,
+        filter: FilterType::NoFilter,
+    };
+    assert!(
+        png_len(&text, Pipeline::new(), &tuned) * 2
+            < png_len(&text, Pipeline::new(), &PngOptions::default())
+    );
+    // adaptive filtering hides the repeated strings of text, which the
+    // planar layout gives back, but the fast default doesn't look for them
+    let adaptive = PngOptions {
+        compression: CompressionType::Default,
+        filter: FilterType::Adaptive,
+    };
+    let planar = png_len(&text, Pipeline::new().with(Planar), &adaptive);
+    assert!(planar * 2 < png_len(&text, Pipeline::new(), &adaptive));
+    assert!(planar < png_len(&text, Pipeline::new(), &tuned) * 11 / 10);
+    let default = PngOptions::default();
+    assert!(
+        png_len(&text, Pipeline::new(), &default)
+            <= png_len(&text, Pipeline::new().with(Planar), &default)
+    );
+}
+
+#[test]
+fn carrier_test() {
+    let header = Header {
+        file_name: Some("test.txt".to_owned()),
+        ..Header::new()
+    };
+    let data = fs::read("tests/data/test.txt").unwrap();
+    let mut pixels = Vec::new();
+    let image = encode::with_header(&header, &data);
+    encode::write_png(
+        &image,
+        &mut Cursor::new(&mut pixels),
+        &PngOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(vec![Carrier::Pixels], decode::survey(&pixels));
+    assert_eq!(Carrier::Pixels, decode::find_carrier(&pixels).unwrap());
+    assert!(decode::from_carrier(&pixels, Carrier::Chunk).is_err());
+
+    // another file's image is just a cover, its pixels stay readable
+    let payload = b"carried".repeat(1000);
+    for carrier in [Carrier::Chunk, Carrier::Trailer] {
+        let png = encode::carry(&pixels, carrier, &header, &payload).unwrap();
+        assert_eq!(carrier, decode::find_carrier(&png).unwrap());
+        assert_eq!(vec![Carrier::Pixels, carrier], decode::survey(&png));
+        let (read, carried) = decode::from_carrier(&png, carrier).unwrap();
+        assert_eq!(Some(header.clone()), read);
+        assert_eq!(payload, carried);
+        let (_, decoded) = decode::from_carrier(&png, Carrier::Pixels).unwrap();
+        assert_eq!(data, decoded);
+    }
+
+    // re-encoding the image, as hosts do, only keeps the pixels
+    let png = encode::carry(&pixels, Carrier::Chunk, &header, &payload).unwrap();
+    let image = decode::image_from_file(Cursor::new(&png)).unwrap();
+    let mut reencoded = Vec::new();
+    encode::write_png(
+        &image,
+        &mut Cursor::new(&mut reencoded),
+        &PngOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(vec![Carrier::Pixels], decode::survey(&reencoded));
+    assert!(encode::carry(&pixels, Carrier::Pixels, &header, &payload).is_err());
+}
+
+#[test]
+fn calibration_test() {
+    let header = Header {
+        file_name: Some("test.txt".to_owned()),
+        banner_rows: Label::ROWS,
+        ..Header::new()
+    };
+    let data = b"filegram ".repeat(3000);
+    let rgb = encode::Encoder::with_header(&header, &data[..])
+        .label(&Label::default())
+        .into_image();
+    assert!(matches!(
+        decode::frame_image(&rgb).unwrap(),
+        Cow::Borrowed(_)
+    ));
+
+    // upscaled by a host, then screenshotted on a page, with alpha
+    let marked = calibration::mark(&rgb, 2);
+    for scale in [2.0, 1.5] {
+        let (width, height) = (
+            (marked.width() as f64 * scale) as u32,
+            (marked.height() as f64 * scale) as u32,
+        );
+        let scaled = imageops::resize(&marked, width, height, imageops::FilterType::Nearest);
+        let mut shot = RgbaImage::from_pixel(width + 120, height + 80, Rgba([40, 44, 52, 255]));
+        imageops::overlay(
+            &mut shot,
+            &DynamicImage::ImageRgb8(scaled).to_rgba8(),
+            70,
+            30,
+        );
+        let mut png = Vec::new();
+        shot.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+
+        let image = decode::image_from_file(Cursor::new(&png)).unwrap();
+        assert_eq!(rgb, *decode::frame_image(&image).unwrap());
+        let (read, payload) = decode::from_carrier(&png, Carrier::Pixels).unwrap();
+        assert_eq!((Some(header.clone()), data.clone()), (read, payload));
+    }
+    assert!(decode::frame_image(&imageops::crop_imm(&marked, 0, 0, 100, 100).to_image()).is_err());
+}
+
+#[test]
+fn convergent_test() {
+    let team = Key::generate();
+    let convergent = |data: &[u8]| {
+        let mut header = Header {
+            encrypted: true,
+            file_name: Some("test.txt".to_owned()),
+            ..Header::new()
+        };
+        let aad = header.associated_data();
+        let cipher = Cipher::convergent(&team, data, &aad);
+        let slot = KeySlot::seal_convergent(cipher.key(), &team, &aad).unwrap();
+        header.key_slots.push(slot);
+        encode::with_header(&header, &cipher.encrypt(data, &aad))
+    };
+    let rgb = convergent(b"filegram");
+
+    assert_eq!(rgb, convergent(b"filegram"));
+    assert_ne!(rgb, convergent(b"filegrams"));
+
+    let (header, payload) = decode::split_header(&rgb).unwrap();
+    let header = header.unwrap();
+    let key = keyslot::open(&header, &Secret::MasterKey(team)).unwrap();
+    let data = Cipher::load(&key)
+        .decrypt(&payload, &header.associated_data())
+        .unwrap();
+    assert_eq!(b"filegram".to_vec(), data);
+}
+
+#[test]
+fn progress_cancel_test() {
+    let header = Header::new();
+    // several batches of rows
+    let data = b"filegram ".repeat(100_000);
+    let mut statuses: Vec<Status> = Vec::new();
+    let rgb = encode::with_header_cancellable(
+        &header,
+        &data,
+        &mut |status| statuses.push(status),
+        &Cancel::new(),
+    )
+    .unwrap();
+    assert!(statuses.len() > 1);
+    assert!(statuses.windows(2).all(|s| s[0].rows_done < s[1].rows_done));
+    let last = statuses.last().unwrap();
+    assert_eq!(
+        (last.rows_done, last.rows_total),
+        (rgb.height() as usize, rgb.height() as usize)
+    );
+    assert_eq!(
+        (last.bytes_done, last.bytes_total),
+        (data.len(), data.len())
+    );
+
+    let cancel = Cancel::new();
+    let mut updates = 0;
+    let cancelled = decode::split_header_cancellable(
+        &rgb,
+        &mut |_| {
+            updates += 1;
+            cancel.cancel();
+        },
+        &cancel,
+    );
+    assert!(cancelled.unwrap_err().is::<Cancelled>());
+    assert_eq!(1, updates);
+    assert_eq!(rgb, encode::with_header(&header, &data));
+    assert_eq!(data, decode::split_header(&rgb).unwrap().1);
+
+    let mut decoder = decode::Decoder::new(rgb.clone()).unwrap();
+    assert_eq!(Some(&header), decoder.header());
+    let mut steps = 1;
+    while !decoder.step(100) {
+        steps += 1;
+    }
+    assert!(steps > 1);
+    let status = decoder.status();
+    assert_eq!(status.rows_done, status.rows_total);
+    assert_eq!(status.bytes_done, status.bytes_total);
+    assert_eq!((Some(header.clone()), data.clone()), decoder.into_parts());
+    let cancel = Cancel::new();
+    cancel.cancel();
+    let decoder = decode::Decoder::new(rgb).unwrap();
+    assert_eq!(Err(Cancelled), decoder.run(&mut |_| {}, &cancel));
+}
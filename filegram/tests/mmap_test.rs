@@ -0,0 +1,37 @@
+#![cfg(feature = "mmap")]
+
+use std::{env, fs, process};
+
+use filegram::{decode, encode::Encoder, header::Header, mmap};
+
+#[test]
+fn mmap_encode_decode_test() {
+    let dir = env::temp_dir().join(format!("filegram-mmap-test-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let header = Header {
+        file_name: Some("test.txt".to_owned()),
+        ..Header::new()
+    };
+
+    for (name, data) in [
+        ("data", b"filegram ".repeat(100_000)),
+        ("empty", Vec::new()),
+    ] {
+        let input_path = dir.join(name);
+        fs::write(&input_path, &data).unwrap();
+        let input = mmap::open(&input_path).unwrap();
+        let image = Encoder::with_header(&header, &input[..]).into_image();
+
+        assert_eq!(Some(header.clone()), decode::read_header(&image).unwrap());
+        let output_path = dir.join(format!("{name}.decoded"));
+        let mut output = mmap::create(&output_path, decode::payload_len(&image).unwrap()).unwrap();
+        decode::payload_into(&image, &mut output).unwrap();
+        output.flush().unwrap();
+        drop(output);
+        assert_eq!(data, fs::read(&output_path).unwrap());
+
+        let mut short = vec![0; data.len().saturating_sub(1)];
+        assert!(data.is_empty() || decode::payload_into(&image, &mut short).is_err());
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,115 @@
+//! Runs against a SoftHSM token, set up with e.g.
+//!
+//! ```sh
+//! softhsm2-util --init-token --free --label filegram --pin 1234 --so-pin 1234
+//! FILEGRAM_TEST_PKCS11_MODULE=/usr/lib/softhsm/libsofthsm2.so \
+//! FILEGRAM_TEST_PKCS11_TOKEN=filegram FILEGRAM_TEST_PKCS11_PIN=1234 \
+//!     cargo test -p filegram --features pkcs11 --test pkcs11_test -- --ignored
+//! ```
+//!
+//! The tests are ignored by default and fail when run without the variables.
+#![cfg(feature = "pkcs11")]
+
+use std::env;
+
+use cryptoki::{
+    context::{CInitializeArgs, CInitializeFlags, Pkcs11},
+    mechanism::Mechanism,
+    object::{Attribute, ObjectHandle},
+    session::{Session, UserType},
+    types::AuthPin,
+};
+use filegram::{
+    encryption::{pkcs11, Cipher, Key},
+    header::Header,
+};
+
+struct SoftHsm {
+    module: String,
+    token: String,
+    pin: String,
+}
+
+impl SoftHsm {
+    fn from_env() -> Self {
+        let var = |name| env::var(name).unwrap_or_else(|_| panic!("{name} not set"));
+        SoftHsm {
+            module: var("FILEGRAM_TEST_PKCS11_MODULE"),
+            token: var("FILEGRAM_TEST_PKCS11_TOKEN"),
+            pin: var("FILEGRAM_TEST_PKCS11_PIN"),
+        }
+    }
+
+    fn session(&self) -> Session {
+        let pkcs11 = Pkcs11::new(&self.module).unwrap();
+        pkcs11
+            .initialize(CInitializeArgs::new(CInitializeFlags::OS_LOCKING_OK))
+            .unwrap();
+        let slot = pkcs11
+            .get_slots_with_token()
+            .unwrap()
+            .into_iter()
+            .find(|slot| pkcs11.get_token_info(*slot).unwrap().label() == self.token)
+            .expect("SoftHSM token not found");
+        let session = pkcs11.open_rw_session(slot).unwrap();
+        session
+            .login(UserType::User, Some(&AuthPin::from(self.pin.as_str())))
+            .unwrap();
+        session
+    }
+
+    /// Creates an AES key on the token, labelled `label`.
+    fn generate_key(&self, session: &Session, label: &str) -> ObjectHandle {
+        let template = [
+            Attribute::Token(true),
+            Attribute::Label(label.as_bytes().to_vec()),
+            Attribute::ValueLen(32.into()),
+            Attribute::Encrypt(true),
+            Attribute::Decrypt(true),
+            Attribute::Sensitive(true),
+            Attribute::Extractable(false),
+        ];
+        session
+            .generate_key(&Mechanism::AesKeyGen, &template)
+            .unwrap()
+    }
+
+    fn uri(&self, label: &str) -> String {
+        format!(
+            "pkcs11:token={};object={label}?module-path={}&pin-value={}",
+            self.token, self.module, self.pin
+        )
+    }
+}
+
+#[test]
+#[ignore = "needs a SoftHSM token, see the module docs"]
+fn token_key_slot_test() {
+    let softhsm = SoftHsm::from_env();
+    let session = softhsm.session();
+    let label = format!("filegram-test-{}", Key::generate().id());
+    let token_key = softhsm.generate_key(&session, &label);
+    let other_label = format!("filegram-test-{}", Key::generate().id());
+    let other_key = softhsm.generate_key(&session, &other_label);
+
+    let cipher = Cipher::new();
+    let mut header = Header {
+        encrypted: true,
+        ..Header::new()
+    };
+    let aad = header.associated_data();
+    let token = pkcs11::Token::connect(&softhsm.uri(&label), None).unwrap();
+    header
+        .key_slots
+        .push(token.seal(cipher.key(), &aad).unwrap());
+
+    let bytes = header.to_bytes();
+    let (header, _) = Header::from_bytes(&bytes).unwrap();
+    assert_eq!(cipher.key(), &pkcs11::open(&header, &token).unwrap());
+    assert!(token.open(&header.key_slots[0], b"other").is_err());
+    let other = pkcs11::Token::connect(&softhsm.uri(&other_label), None).unwrap();
+    assert!(pkcs11::open(&header, &other).is_err());
+
+    session.destroy_object(token_key).unwrap();
+    session.destroy_object(other_key).unwrap();
+}